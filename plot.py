@@ -17,10 +17,11 @@ async def task(mw):
         if not line:
             print("input process died")
             break
-        number = int(line.decode().strip())
-        data.append(number)
+        numbers = [float(column) for column in line.decode().split()]
+        data.append(numbers)
         widget.clear()
-        widget.plot(data)
+        for i in range(len(numbers)):
+            widget.plot([row[i] for row in data], pen=pyqtgraph.intColor(i))
 
 
 
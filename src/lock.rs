@@ -0,0 +1,223 @@
+use serde_derive::Deserialize;
+
+use std::fs::File;
+use std::io::Write;
+use std::net::UdpSocket;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+
+#[derive(Deserialize, Debug)]
+pub struct LockConfig {
+    setpoint: f64,          // Target wavelength in nm.
+
+    // PID gains, in output units per nm of error (kp), per nm*s (ki) and per nm/s (kd).
+    // The error is the setpoint minus the measured wavelength.
+    kp: f64,
+    ki: f64,
+    kd: f64,
+
+    output_min: f64,        // Lower limit of the control value.
+    output_max: f64,        // Upper limit of the control value.
+    tolerance: f64,         // Maximum absolute error in nm for the lock to be reported as locked.
+
+    sink: SinkConfig,       // Where the control value is sent.
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkConfig {
+    // Shell command started once, receiving one control value per line on its standard input.
+    Command(String),
+    // File overwritten with the control value at each update (e.g. a sysfs DAC attribute).
+    File(String),
+    // UDP address (host:port) to which each control value is sent as a text datagram.
+    Udp(String),
+}
+
+pub trait Sink: Send {
+    fn write(&mut self, value: f64) -> std::io::Result<()>;
+}
+
+struct CommandSink {
+    child: Child
+}
+
+impl Sink for CommandSink {
+    fn write(&mut self, value: f64) -> std::io::Result<()> {
+        let stdin = self.child.stdin.as_mut().unwrap();
+        writeln!(stdin, "{}", value)?;
+        stdin.flush()
+    }
+}
+
+struct FileSink {
+    path: String
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, value: f64) -> std::io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        writeln!(file, "{}", value)
+    }
+}
+
+struct UdpSink {
+    socket: UdpSocket,
+    address: String
+}
+
+impl Sink for UdpSink {
+    fn write(&mut self, value: f64) -> std::io::Result<()> {
+        self.socket.send_to(format!("{}\n", value).as_bytes(), &self.address)?;
+        Ok(())
+    }
+}
+
+fn create_sink(config: &SinkConfig) -> std::io::Result<Box<dyn Sink>> {
+    Ok(match config {
+        SinkConfig::Command(command) => {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .spawn()?;
+            Box::new(CommandSink { child: child })
+        },
+        SinkConfig::File(path) => Box::new(FileSink { path: path.clone() }),
+        SinkConfig::Udp(address) => Box::new(UdpSink {
+            socket: UdpSocket::bind("0.0.0.0:0")?,
+            address: address.clone()
+        }),
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    Saturated,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LockStatus {
+    pub error: f64,
+    pub output: f64,
+    pub state: LockState,
+}
+
+pub struct Lock<'a> {
+    config: &'a LockConfig,
+    sender: mpsc::Sender<f64>,
+    integrator: f64,
+    last_error: Option<f64>,
+    last_time: f64,
+    last_output: f64,
+}
+
+impl<'a> Lock<'a> {
+    pub fn new(config: &'a LockConfig) -> std::io::Result<Lock<'a>> {
+        // The control values are written to the sink in a background thread, so that slow
+        // sinks do not block the processing of the samples. Only the most recent value is
+        // written when the sink falls behind.
+        let mut sink = create_sink(&config.sink)?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Ok(mut value) = receiver.recv() {
+                for newer in receiver.try_iter() {
+                    value = newer;
+                }
+                if let Err(e) = sink.write(value) {
+                    eprintln!("failed to write lock output: {}", e);
+                }
+            }
+        });
+        Ok(Lock {
+            config: config,
+            sender: sender,
+            integrator: 0.0,
+            last_error: None,
+            last_time: 0.0,
+            last_output: std::f64::NAN,
+        })
+    }
+
+    // Updates the controller with a new wavelength measurement (in nm) taken at the given
+    // time (in s), and sends the new control value to the sink. Measurements that are not
+    // finite leave the controller and its output unchanged.
+    pub fn update(&mut self, time: f64, wavelength: f64) -> LockStatus {
+        let config = self.config;
+        let error = config.setpoint - wavelength;
+        if !error.is_finite() {
+            return LockStatus {
+                error: error,
+                output: self.last_output,
+                state: LockState::Unlocked,
+            };
+        }
+        let (dt, derivative) = match self.last_error {
+            Some(last_error) if time > self.last_time => {
+                let dt = time - self.last_time;
+                (dt, (error - last_error)/dt)
+            },
+            _ => (0.0, 0.0)
+        };
+        self.last_error = Some(error);
+        self.last_time = time;
+
+        // Anti-windup: the integrator is only updated when this does not drive
+        // the output further into saturation.
+        let proportional = config.kp*error + config.kd*derivative;
+        let integrator = self.integrator + config.ki*error*dt;
+        let unclamped = proportional + integrator;
+        if (unclamped <= config.output_max || integrator < self.integrator)
+                && (unclamped >= config.output_min || integrator > self.integrator) {
+            self.integrator = integrator;
+        }
+        let unclamped = proportional + self.integrator;
+        let output = unclamped.max(config.output_min).min(config.output_max);
+        self.last_output = output;
+        self.sender.send(output).unwrap();
+
+        let state = if output != unclamped {
+            LockState::Saturated
+        } else if error.abs() <= config.tolerance {
+            LockState::Locked
+        } else {
+            LockState::Unlocked
+        };
+        LockStatus {
+            error: error,
+            output: output,
+            state: state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_skips_non_finite() {
+        let config: LockConfig = serde_json::from_str(r#"{
+            "setpoint": 780.0, "kp": 1.0, "ki": 10.0, "kd": 0.0,
+            "output_min": -5.0, "output_max": 5.0, "tolerance": 0.01,
+            "sink": {"file": "/dev/null"}
+        }"#).unwrap();
+        let mut lock = Lock::new(&config).unwrap();
+        let status = lock.update(0.0, 779.9);
+        assert!((status.output - 0.1).abs() < 1.0e-9);
+        assert_eq!(status.state, LockState::Unlocked);
+        let status = lock.update(1.0, std::f64::NAN);
+        assert!((status.output - 0.1).abs() < 1.0e-9);
+        assert_eq!(status.state, LockState::Unlocked);
+        // The integrator covers the time since the last finite measurement.
+        let status = lock.update(2.0, 779.9);
+        assert!((status.output - 2.1).abs() < 1.0e-9, "{}", status.output);
+        let status = lock.update(3.0, 780.0);
+        assert!((status.output - 2.0).abs() < 1.0e-9, "{}", status.output);
+        assert_eq!(status.state, LockState::Locked);
+        let status = lock.update(4.0, 770.0);
+        assert_eq!((status.output, status.state), (5.0, LockState::Saturated));
+    }
+}
@@ -1,5 +1,10 @@
-use std::io::{BufReader, Read};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
 use num_traits::clamp;
+use serde_derive::Deserialize;
+use biquad::Biquad;
 
 pub struct Dpll {
     ftw_min: i64,
@@ -79,80 +84,858 @@ impl Dpll {
         self.phase_unwrapped
     }
 
+    // Frequency of the tracked signal in Hz, from the loop integrator.
+    pub fn get_frequency(&self, sample_rate: f64) -> f64 {
+        (self.integrator as f64)*sample_rate/(Dpll::TURN as f64)
+    }
+
     pub fn locked(&self) -> bool {
         self.wait_lock.is_none()
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PositionError {
+    Timeout,                // No MEAS edge for longer than the timeout (e.g. beam interruption).
+    Glitch(i64),            // Implausible displacement between two MEAS edges, in TURN units.
+}
+
 pub struct PositionTracker {
     last_phase: i64,
-    current_position: i64
+    current_position: i64,
+    started: bool,
+    max_step: Option<i64>,
+    timeout: Option<i64>,
+    error: Option<PositionError>
 }
 
 impl PositionTracker {
     pub fn new() -> PositionTracker {
+        PositionTracker::with_limits(None, None)
+    }
+
+    // The position becomes invalid if the displacement between two MEAS edges exceeds
+    // max_step, or if the REF phase advances by more than timeout without a MEAS edge
+    // (both in Dpll::TURN units). The error stays latched until clear_error() is called.
+    pub fn with_limits(max_step: Option<i64>, timeout: Option<i64>) -> PositionTracker {
         PositionTracker {
             last_phase: 0,
-            current_position: 0
+            current_position: 0,
+            started: false,
+            max_step: max_step,
+            timeout: timeout,
+            error: None
         }
     }
 
     pub fn edge(&mut self, phase: i64) -> i64 {
         let phase_diff = phase.wrapping_sub(self.last_phase);
         self.last_phase = phase;
-        self.current_position += Dpll::TURN - phase_diff;
+        let step = Dpll::TURN - phase_diff;
+        self.current_position += step;
+        if self.started {
+            if self.timeout.is_some_and(|timeout| phase_diff > timeout) {
+                self.latch_error(PositionError::Timeout);
+            } else if self.max_step.is_some_and(|max_step| step.abs() > max_step) {
+                self.latch_error(PositionError::Glitch(step));
+            }
+        }
+        self.started = true;
         self.current_position
     }
+
+    // Detects timeouts while no MEAS edges are received. To be called regularly,
+    // e.g. at each REF edge.
+    pub fn check(&mut self, phase: i64) {
+        if let Some(timeout) = self.timeout {
+            if self.started && phase.wrapping_sub(self.last_phase) > timeout {
+                self.latch_error(PositionError::Timeout);
+            }
+        }
+    }
+
+    fn latch_error(&mut self, error: PositionError) {
+        if self.error.is_none() {
+            eprintln!("position invalid: {:?}", error);
+            self.error = Some(error);
+        }
+    }
+
+    pub fn error(&self) -> Option<PositionError> {
+        self.error
+    }
+
+    pub fn clear_error(&mut self) {
+        self.error = None;
+    }
 }
 
-pub struct Decimator<T> {
-    accumulator: T,
+// Mean of each block of max_count inputs, accumulated in 128 bits so that large positions
+// do not overflow.
+pub struct Decimator {
+    accumulator: i128,
     current_count: u32,
     max_count: u32
 }
 
-impl<T> Decimator<T> {
-    pub fn new(max_count: u32) -> Decimator<T> where T: std::convert::From<u32> {
+impl Decimator {
+    pub fn new(max_count: u32) -> Decimator {
         Decimator {
-            accumulator: T::from(0),
+            accumulator: 0,
             current_count: 0,
             max_count: max_count
         }
     }
 
-    pub fn input(&mut self, data: T) -> Option<T>
-            where T: Copy +
-                std::convert::From<u32> +
-                std::convert::From<<T as std::ops::Div>::Output> +
-                std::ops::AddAssign +
-                std::ops::Div {
-        self.accumulator += data;
+    pub fn input(&mut self, data: i64) -> Option<i64> {
+        self.accumulator += data as i128;
         self.current_count += 1;
         if self.current_count == self.max_count {
-            let average = T::from(self.accumulator/T::from(self.current_count));
-            self.accumulator = T::from(0);
+            let average = self.accumulator/(self.current_count as i128);
+            self.accumulator = 0;
             self.current_count = 0;
-            Some(average)
+            Some(average as i64)
         } else {
             None
         }
     }
 }
 
-pub fn sample(command: &str, mut callback: impl FnMut(u8, u8)) {
+// Common interface of the decimators of integer samples. Each output consists of the
+// values named by outputs(), the first one being the main value (e.g. the mean).
+pub trait Decimate {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>>;
+
+    // Name suffixes of the output values.
+    fn outputs(&self) -> &'static [&'static str] {
+        &[""]
+    }
+
+    // Group delay in input samples.
+    fn delay(&self) -> f64;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DecimatorConfig {
+    // Mean of each block of inputs.
+    Average,
+    // CIC filter, optionally followed by a FIR filter compensating the passband droop.
+    // The gain ratio^stages must not exceed 2^64.
+    Cic { stages: u32, #[serde(default)] compensation: bool },
+    // Mean of the last length inputs.
+    MovingAverage { length: u32 },
+    // Median of each block of inputs, rejecting glitches.
+    Median,
+    // Mean, minimum and maximum of each block of inputs.
+    Envelope,
+}
+
+impl DecimatorConfig {
+    // Creates a decimator producing one output every ratio inputs.
+    pub fn create(&self, ratio: u32) -> Result<Box<dyn Decimate>, String> {
+        Ok(match *self {
+            DecimatorConfig::Average => Box::new(Decimator::new(ratio)),
+            DecimatorConfig::Cic { stages, compensation } => if compensation {
+                Box::new(CompensatedCic::new(stages, ratio)?)
+            } else {
+                Box::new(Cic::new(stages, ratio)?)
+            },
+            DecimatorConfig::MovingAverage { length } => Box::new(MovingAverage::new(length, ratio)?),
+            DecimatorConfig::Median => Box::new(BlockMedian::new(ratio)),
+            DecimatorConfig::Envelope => Box::new(Envelope::new(ratio)),
+        })
+    }
+}
+
+impl Decimate for Decimator {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        Decimator::input(self, data).map(|average| vec![average])
+    }
+
+    fn delay(&self) -> f64 {
+        ((self.max_count - 1) as f64)/2.0
+    }
+}
+
+// Cascaded integrator-comb decimator. The integrators wrap around, and the output of the
+// combs is only exact if the gain times the magnitude of the inputs fits in i128; the gain
+// is therefore limited to 2^64.
+pub struct Cic {
+    ratio: u32,
+    count: u32,
+    integrators: Vec<i128>,
+    combs: Vec<i128>,
+    gain: i128,
+    settling: u32,
+}
+
+impl Cic {
+    pub fn new(stages: u32, ratio: u32) -> Result<Cic, String> {
+        if stages < 1 || ratio < 1 {
+            return Err("CIC stages and ratio must be at least 1".to_string());
+        }
+        let gain = (ratio as i128).checked_pow(stages).filter(|&gain| gain <= 1 << 64)
+            .ok_or_else(|| format!("CIC gain {}^{} exceeds 2^64", ratio, stages))?;
+        Ok(Cic {
+            ratio: ratio,
+            count: 0,
+            integrators: vec![0; stages as usize],
+            combs: vec![0; stages as usize],
+            gain: gain,
+            settling: stages,
+        })
+    }
+
+    pub fn input(&mut self, data: i64) -> Option<i64> {
+        let mut x = data as i128;
+        for integrator in self.integrators.iter_mut() {
+            *integrator = integrator.wrapping_add(x);
+            x = *integrator;
+        }
+        self.count += 1;
+        if self.count < self.ratio {
+            return None;
+        }
+        self.count = 0;
+        for comb in self.combs.iter_mut() {
+            let delayed = *comb;
+            *comb = x;
+            x = x.wrapping_sub(delayed);
+        }
+        // The first outputs are incomplete until the combs are filled.
+        if self.settling > 0 {
+            self.settling -= 1;
+            return None;
+        }
+        Some((x + self.gain/2).div_euclid(self.gain) as i64)
+    }
+
+    // Group delay in input samples.
+    pub fn delay(&self) -> f64 {
+        (self.integrators.len() as f64)*((self.ratio - 1) as f64)/2.0
+    }
+}
+
+impl Decimate for Cic {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        Cic::input(self, data).map(|output| vec![output])
+    }
+
+    fn delay(&self) -> f64 {
+        Cic::delay(self)
+    }
+}
+
+// CIC decimator followed by the three-tap FIR filter (-a, 1 + 2a, -a) at the output rate,
+// which compensates the passband droop of the CIC to second order with a = stages/24.
+pub struct CompensatedCic {
+    cic: Cic,
+    a: f64,
+    history: [i64; 2],
+    settling: u32,
+}
+
+impl CompensatedCic {
+    pub fn new(stages: u32, ratio: u32) -> Result<CompensatedCic, String> {
+        Ok(CompensatedCic {
+            cic: Cic::new(stages, ratio)?,
+            a: (stages as f64)/24.0,
+            history: [0; 2],
+            settling: 2,
+        })
+    }
+}
+
+impl Decimate for CompensatedCic {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        let x = self.cic.input(data)?;
+        let [x1, x2] = self.history;
+        self.history = [x, x1];
+        if self.settling > 0 {
+            self.settling -= 1;
+            return None;
+        }
+        // Relative to x1 to preserve the precision of large positions.
+        let y = x1 as f64 - self.a*(((x - x1) + (x2 - x1)) as f64);
+        Some(vec![y.round() as i64])
+    }
+
+    fn delay(&self) -> f64 {
+        self.cic.delay() + (self.cic.ratio as f64)
+    }
+}
+
+// Mean of the last length inputs, output every ratio inputs.
+pub struct MovingAverage {
+    window: std::collections::VecDeque<i64>,
+    length: usize,
+    sum: i128,
+    ratio: u32,
+    count: u32,
+}
+
+impl MovingAverage {
+    pub fn new(length: u32, ratio: u32) -> Result<MovingAverage, String> {
+        if length < 1 {
+            return Err("moving average length must be at least 1".to_string());
+        }
+        Ok(MovingAverage {
+            window: std::collections::VecDeque::with_capacity(length as usize),
+            length: length as usize,
+            sum: 0,
+            ratio: ratio,
+            count: 0,
+        })
+    }
+}
+
+impl Decimate for MovingAverage {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        self.window.push_back(data);
+        self.sum += data as i128;
+        if self.window.len() > self.length {
+            self.sum -= self.window.pop_front().unwrap() as i128;
+        }
+        self.count += 1;
+        if self.count < self.ratio {
+            return None;
+        }
+        self.count = 0;
+        if self.window.len() < self.length {
+            return None;
+        }
+        Some(vec![(self.sum/(self.length as i128)) as i64])
+    }
+
+    fn delay(&self) -> f64 {
+        ((self.length - 1) as f64)/2.0
+    }
+}
+
+// Median of each block of ratio inputs.
+pub struct BlockMedian {
+    block: Vec<i64>,
+    ratio: usize,
+}
+
+impl BlockMedian {
+    pub fn new(ratio: u32) -> BlockMedian {
+        BlockMedian {
+            block: Vec::with_capacity(ratio as usize),
+            ratio: ratio as usize,
+        }
+    }
+}
+
+impl Decimate for BlockMedian {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        self.block.push(data);
+        if self.block.len() < self.ratio {
+            return None;
+        }
+        let middle = self.block.len()/2;
+        let median = *self.block.select_nth_unstable(middle).1;
+        self.block.clear();
+        Some(vec![median])
+    }
+
+    fn delay(&self) -> f64 {
+        ((self.ratio - 1) as f64)/2.0
+    }
+}
+
+// Mean, minimum and maximum of each block of ratio inputs.
+pub struct Envelope {
+    sum: i128,
+    min: i64,
+    max: i64,
+    count: u32,
+    ratio: u32,
+}
+
+impl Envelope {
+    pub fn new(ratio: u32) -> Envelope {
+        Envelope {
+            sum: 0,
+            min: i64::max_value(),
+            max: i64::min_value(),
+            count: 0,
+            ratio: ratio,
+        }
+    }
+}
+
+impl Decimate for Envelope {
+    fn input(&mut self, data: i64) -> Option<Vec<i64>> {
+        self.sum += data as i128;
+        self.min = self.min.min(data);
+        self.max = self.max.max(data);
+        self.count += 1;
+        if self.count < self.ratio {
+            return None;
+        }
+        let output = vec![(self.sum/(self.count as i128)) as i64, self.min, self.max];
+        *self = Envelope::new(self.ratio);
+        Some(output)
+    }
+
+    fn outputs(&self) -> &'static [&'static str] {
+        &["", ".min", ".max"]
+    }
+
+    fn delay(&self) -> f64 {
+        ((self.ratio - 1) as f64)/2.0
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterDesign {
+    // Low-pass filters of the given order, with the cut-off frequency (-3dB) in Hz.
+    Butterworth { order: u32, cutoff: f64 },
+    Bessel { order: u32, cutoff: f64 },
+    // Cascade of biquad sections with normalized coefficients (a0 = 1).
+    Custom(Vec<FilterSection>),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct FilterSection {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+// Frequency scaling factors and Q of the second-order sections of Bessel filters
+// normalized for -3dB at the cut-off frequency, for orders 2 to 8. Odd orders have an
+// additional first-order section with the frequency scaling factor given separately.
+const BESSEL_SECTIONS: [(f64, &[(f64, f64)]); 7] = [
+    (0.0, &[(1.2736, 0.5773)]),
+    (1.3270, &[(1.4524, 0.6910)]),
+    (0.0, &[(1.4192, 0.5219), (1.5912, 0.8055)]),
+    (1.5069, &[(1.5611, 0.5635), (1.7607, 0.9165)]),
+    (0.0, &[(1.6060, 0.5103), (1.6913, 0.6112), (1.9071, 1.0234)]),
+    (1.6853, &[(1.7174, 0.5324), (1.8235, 0.6608), (2.0507, 1.1262)]),
+    (0.0, &[(1.7837, 0.5060), (1.8376, 0.5596), (1.9591, 0.7109), (2.1953, 1.2258)]),
+];
+
+pub struct FilterCascade {
+    coefficients: Vec<biquad::Coefficients<f64>>,
+    sections: Vec<biquad::DirectForm2Transposed<f64>>,
+}
+
+impl FilterCascade {
+    pub fn new(design: &FilterDesign, sample_rate: f64) -> Result<FilterCascade, String> {
+        let section = |filter_type, frequency: f64, q| {
+            biquad::Coefficients::<f64>::from_params(
+                filter_type,
+                biquad::frequency::Hertz::<f64>::from_hz(sample_rate).unwrap(),
+                biquad::frequency::Hertz::<f64>::from_hz(frequency).map_err(|e| format!("{:?}", e))?,
+                q).map_err(|e| format!("{:?}", e))
+        };
+        let mut coefficients = Vec::new();
+        match design {
+            FilterDesign::Butterworth { order, cutoff } => {
+                if *order < 1 {
+                    return Err("filter order must be at least 1".to_string());
+                }
+                if order % 2 == 1 {
+                    coefficients.push(section(biquad::Type::SinglePoleLowPass, *cutoff, 0.0)?);
+                }
+                // Pole angles from the negative real axis.
+                for k in 1..=order/2 {
+                    let theta = if order % 2 == 0 {
+                        ((2*k - 1) as f64)*std::f64::consts::PI/((2*order) as f64)
+                    } else {
+                        (k as f64)*std::f64::consts::PI/(*order as f64)
+                    };
+                    coefficients.push(section(biquad::Type::LowPass, *cutoff, 1.0/(2.0*theta.cos()))?);
+                }
+            },
+            FilterDesign::Bessel { order, cutoff } => {
+                if *order < 2 || *order > 8 {
+                    return Err("Bessel filter order must be between 2 and 8".to_string());
+                }
+                let (first_order, second_order) = BESSEL_SECTIONS[(order - 2) as usize];
+                if first_order != 0.0 {
+                    coefficients.push(section(biquad::Type::SinglePoleLowPass, cutoff*first_order, 0.0)?);
+                }
+                for &(scale, q) in second_order.iter() {
+                    coefficients.push(section(biquad::Type::LowPass, cutoff*scale, q)?);
+                }
+            },
+            FilterDesign::Custom(sections) => {
+                for s in sections.iter() {
+                    coefficients.push(biquad::Coefficients {
+                        a1: s.a1, a2: s.a2, b0: s.b0, b1: s.b1, b2: s.b2
+                    });
+                }
+            }
+        }
+        Ok(FilterCascade {
+            sections: coefficients.iter().map(|&c| biquad::DirectForm2Transposed::<f64>::new(c)).collect(),
+            coefficients: coefficients,
+        })
+    }
+
+    pub fn run(&mut self, input: f64) -> f64 {
+        self.sections.iter_mut().fold(input, |x, section| section.run(x))
+    }
+
+    // Complex frequency response at the given normalized angular frequency (rad/sample).
+    fn response(&self, omega: f64) -> (f64, f64) {
+        let mul = |a: (f64, f64), b: (f64, f64)| (a.0*b.0 - a.1*b.1, a.0*b.1 + a.1*b.0);
+        let z1 = (omega.cos(), -omega.sin());
+        let z2 = mul(z1, z1);
+        self.coefficients.iter().fold((1.0, 0.0), |h, c| {
+            let num = (c.b0 + c.b1*z1.0 + c.b2*z2.0, c.b1*z1.1 + c.b2*z2.1);
+            let den = (1.0 + c.a1*z1.0 + c.a2*z2.0, c.a1*z1.1 + c.a2*z2.1);
+            let den2 = den.0*den.0 + den.1*den.1;
+            mul(h, mul(num, (den.0/den2, -den.1/den2)))
+        })
+    }
+
+    // Attenuation in dB at the given frequency in Hz.
+    pub fn attenuation(&self, frequency: f64, sample_rate: f64) -> f64 {
+        let h = self.response(2.0*std::f64::consts::PI*frequency/sample_rate);
+        -10.0*(h.0*h.0 + h.1*h.1).log10()
+    }
+
+    // Group delay in samples at the given frequency in Hz.
+    pub fn group_delay(&self, frequency: f64, sample_rate: f64) -> f64 {
+        let omega = 2.0*std::f64::consts::PI*frequency/sample_rate;
+        let delta = 1.0e-6;
+        let h1 = self.response(omega);
+        let h2 = self.response(omega + delta);
+        // Phase of h2/h1
+        let phase = (h2.1*h1.0 - h2.0*h1.1).atan2(h2.0*h1.0 + h2.1*h1.1);
+        -phase/delta
+    }
+}
+
+// Estimates the velocity and acceleration from positions sampled at a regular rate
+// (e.g. at the REF edges), by differentiating and low-pass filtering with a second-order
+// Butterworth filter of the given bandwidth at each stage.
+pub struct MotionEstimator {
+    last_position: Option<i64>,
+    last_velocity: f64,
+    velocity_filter: FilterCascade,
+    acceleration_filter: FilterCascade,
+    velocity: f64,
+    acceleration: f64,
+}
+
+impl MotionEstimator {
+    // The nominal sample rate is used for the design of the filters.
+    pub fn new(bandwidth: f64, sample_rate: f64) -> Result<MotionEstimator, String> {
+        let design = FilterDesign::Butterworth { order: 2, cutoff: bandwidth };
+        Ok(MotionEstimator {
+            last_position: None,
+            last_velocity: 0.0,
+            velocity_filter: FilterCascade::new(&design, sample_rate)?,
+            acceleration_filter: FilterCascade::new(&design, sample_rate)?,
+            velocity: 0.0,
+            acceleration: 0.0,
+        })
+    }
+
+    // Position in any unit, sample_rate is the actual rate at which positions are input.
+    pub fn input(&mut self, position: i64, sample_rate: f64) {
+        if let Some(last_position) = self.last_position {
+            let velocity = ((position - last_position) as f64)*sample_rate;
+            self.velocity = self.velocity_filter.run(velocity);
+            let acceleration = (self.velocity - self.last_velocity)*sample_rate;
+            self.acceleration = self.acceleration_filter.run(acceleration);
+            self.last_velocity = self.velocity;
+        }
+        self.last_position = Some(position);
+    }
+
+    // Velocity in position units per s.
+    pub fn velocity(&self) -> f64 {
+        self.velocity
+    }
+
+    // Acceleration in position units per s^2.
+    pub fn acceleration(&self) -> f64 {
+        self.acceleration
+    }
+}
+
+// In-place radix-2 FFT of the complex sequence (re, im). The length must be a power of 2.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0*std::f64::consts::PI/(length as f64);
+        for start in (0..n).step_by(length) {
+            for k in 0..length/2 {
+                let (sin, cos) = (angle*(k as f64)).sin_cos();
+                let (a, b) = (start + k, start + k + length/2);
+                let tr = re[b]*cos - im[b]*sin;
+                let ti = re[b]*sin + im[b]*cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+// One-sided power spectral density (in units^2/Hz) of data by Welch's method, with
+// linearly detrended and Hann-windowed segments of the given length overlapping by half.
+// Returns segment/2 + 1 values spaced by sample_rate/segment.
+pub fn welch_psd(data: &[f64], segment: usize, sample_rate: f64) -> Vec<f64> {
+    let window: Vec<f64> = (0..segment)
+        .map(|i| 0.5 - 0.5*(2.0*std::f64::consts::PI*(i as f64)/(segment as f64)).cos())
+        .collect();
+    let window_power: f64 = window.iter().map(|w| w*w).sum();
+    let center = ((segment - 1) as f64)/2.0;
+    let index_variance: f64 = (0..segment).map(|i| ((i as f64) - center).powi(2)).sum();
+    let mut psd = vec![0.0; segment/2 + 1];
+    let mut count = 0;
+    let mut start = 0;
+    while start + segment <= data.len() {
+        let x = &data[start..start + segment];
+        let mean = x.iter().sum::<f64>()/(segment as f64);
+        let slope = x.iter().enumerate()
+            .map(|(i, v)| ((i as f64) - center)*(v - mean)).sum::<f64>()/index_variance;
+        let mut re: Vec<f64> = x.iter().zip(window.iter()).enumerate()
+            .map(|(i, (v, w))| (v - mean - slope*((i as f64) - center))*w).collect();
+        let mut im = vec![0.0; segment];
+        fft(&mut re, &mut im);
+        for (k, p) in psd.iter_mut().enumerate() {
+            let power = re[k]*re[k] + im[k]*im[k];
+            *p += if k == 0 || k == segment/2 { power } else { 2.0*power };
+        }
+        count += 1;
+        start += segment/2;
+    }
+    for p in psd.iter_mut() {
+        *p /= (count as f64)*sample_rate*window_power;
+    }
+    psd
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AirConditions {
+    pub temperature: f64,   // Temperature in degrees Celsius.
+    pub pressure: f64,      // Pressure in Pa.
+    pub humidity: f64,      // Relative humidity in %.
+    pub co2: f64,           // CO2 concentration in ppm.
+}
+
+impl AirConditions {
+    // Refractive index of moist air (Ciddor, Appl. Opt. 35, 1566 (1996)).
+    // The wavelength is in m (vacuum).
+    pub fn refractive_index(&self, wavelength: f64) -> f64 {
+        let sigma2 = (1.0e-6/wavelength).powi(2);  // um^-2
+        let t = self.temperature;
+        let tk = t + 273.15;
+        let p = self.pressure;
+
+        let n_as = 1.0e-8*(5792105.0/(238.0185 - sigma2) + 167917.0/(57.362 - sigma2));
+        let n_axs = n_as*(1.0 + 0.534e-6*(self.co2 - 450.0));
+        let n_ws = 1.022e-8*(295.235 + 2.6422*sigma2 - 0.032380*sigma2.powi(2)
+            + 0.004028*sigma2.powi(3));
+
+        let svp = (1.2378847e-5*tk*tk - 1.9121316e-2*tk + 33.93711047 - 6.3431645e3/tk).exp();
+        let f = 1.00062 + 3.14e-8*p + 5.6e-7*t*t;
+        let xw = f*self.humidity/100.0*svp/p;
+
+        let r = 8.314510;
+        let ma = 1.0e-3*(28.9635 + 12.011e-6*(self.co2 - 400.0));
+        let mw = 0.018015;
+        let rho_axs = 101325.0*ma/(compressibility(101325.0, 288.15, 0.0)*r*288.15);
+        let rho_ws = 1333.0*mw/(compressibility(1333.0, 293.15, 1.0)*r*293.15);
+        let z = compressibility(p, tk, xw);
+        let rho_a = p*ma*(1.0 - xw)/(z*r*tk);
+        let rho_w = p*mw*xw/(z*r*tk);
+
+        1.0 + rho_a/rho_axs*n_axs + rho_w/rho_ws*n_ws
+    }
+
+    // Converts a wavelength determined by comparison with a reference laser in air
+    // into a vacuum wavelength. Both wavelengths are in m (vacuum).
+    pub fn dispersion_correction(&self, ref_wavelength: f64, wavelength: f64) -> f64 {
+        wavelength*self.refractive_index(wavelength)/self.refractive_index(ref_wavelength)
+    }
+}
+
+fn compressibility(p: f64, tk: f64, xw: f64) -> f64 {
+    let t = tk - 273.15;
+    let pt = p/tk;
+    1.0 - pt*(1.58123e-6 - 2.9331e-8*t + 1.1043e-10*t*t
+            + (5.707e-6 - 2.051e-8*t)*xw + (1.9898e-4 - 2.376e-6*t)*xw*xw)
+        + pt*pt*(1.83e-11 - 0.765e-8*xw*xw)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AirConfig {
+    temperature: f64,       // Temperature in degrees Celsius.
+    pressure: f64,          // Pressure in Pa.
+    humidity: f64,          // Relative humidity in %.
+    co2: f64,               // CO2 concentration in ppm.
+
+    // Optional shell command that prints the temperature, pressure, humidity and CO2
+    // concentration (in the units above, separated by whitespace) on its standard output.
+    // When present, it is run every sensor_period seconds and overrides the values above.
+    sensor_command: Option<String>,
+    sensor_period: Option<f64>,
+}
+
+pub struct AirMonitor {
+    conditions: Arc<Mutex<AirConditions>>
+}
+
+impl AirMonitor {
+    pub fn new(config: &AirConfig) -> AirMonitor {
+        let conditions = Arc::new(Mutex::new(AirConditions {
+            temperature: config.temperature,
+            pressure: config.pressure,
+            humidity: config.humidity,
+            co2: config.co2
+        }));
+        if let Some(command) = config.sensor_command.clone() {
+            let period = Duration::from_millis((1000.0*config.sensor_period.unwrap_or(10.0)) as u64);
+            let conditions = conditions.clone();
+            std::thread::spawn(move || loop {
+                match read_air_sensor(&command) {
+                    Ok(new_conditions) => *conditions.lock().unwrap() = new_conditions,
+                    Err(e) => eprintln!("failed to read air sensor: {}", e)
+                }
+                std::thread::sleep(period);
+            });
+        }
+        AirMonitor {
+            conditions: conditions
+        }
+    }
+
+    pub fn get(&self) -> AirConditions {
+        *self.conditions.lock().unwrap()
+    }
+}
+
+// Environmental compensation of displacements measured by a DMI.
+pub struct DisplacementCompensator {
+    wavelength: f64,        // Vacuum wavelength of the laser, in m.
+    // Distance between the interferometer and the reflector at zero displacement, in m.
+    dead_path: f64,
+    cte: Option<f64>,       // Thermal expansion coefficient of the workpiece, in 1/K.
+    zero_index: Option<f64>,
+}
+
+impl DisplacementCompensator {
+    pub fn new(wavelength: f64, dead_path: f64, cte: Option<f64>) -> DisplacementCompensator {
+        DisplacementCompensator {
+            wavelength: wavelength,
+            dead_path: dead_path,
+            cte: cte,
+            zero_index: None,
+        }
+    }
+
+    // To be called when the displacement is zeroed. The refractive index at the next
+    // compensation becomes the reference for the dead path correction.
+    pub fn reset(&mut self) {
+        self.zero_index = None;
+    }
+
+    // Converts a displacement computed with the vacuum wavelength (in m) into the
+    // displacement in air, including the change of optical length of the dead path since
+    // the displacement was zeroed. If a CTE is set, the result is normalized to 20 °C
+    // using the temperature of the workpiece (in degrees Celsius).
+    pub fn compensate(&mut self, air: Option<&AirConditions>, material_temperature: f64,
+            displacement: f64) -> f64 {
+        let mut displacement = displacement;
+        if let Some(air) = air {
+            let index = air.refractive_index(self.wavelength);
+            let zero_index = *self.zero_index.get_or_insert(index);
+            displacement = (displacement + zero_index*self.dead_path)/index - self.dead_path;
+        }
+        if let Some(cte) = self.cte {
+            displacement /= 1.0 + cte*(material_temperature - 20.0);
+        }
+        displacement
+    }
+}
+
+fn read_air_sensor(command: &str) -> Result<AirConditions, Box<dyn Error>> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()?;
+    let values = String::from_utf8(output.stdout)?
+        .split_whitespace()
+        .map(|s| s.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()?;
+    if values.len() != 4 {
+        return Err(format!("expected 4 values, got {}", values.len()).into());
+    }
+    Ok(AirConditions {
+        temperature: values[0],
+        pressure: values[1],
+        humidity: values[2],
+        co2: values[3]
+    })
+}
+
+// Reads commands from the standard input, one per line, in a background thread.
+// The receiver should be polled with try_recv() from the processing loop.
+pub fn stdin_commands() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = line.unwrap();
+            let line = line.trim();
+            if !line.is_empty() && sender.send(line.to_string()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// The sampling command produces either 4-bit samples packed two per byte (most significant
+// nibble first), or 8-bit samples, one per byte.
+pub fn sample(command: &str, sample_bits: u8, mut callback: impl FnMut(u8, u8)) {
+    assert!(sample_bits == 4 || sample_bits == 8);
     let child = std::process::Command::new("sh")
         .arg("-c")
         .arg(command)
+        .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::piped())
         .spawn()
         .unwrap();  
     let mut reader = BufReader::new(child.stdout.unwrap());
     let mut buffer = [0; 1];
     let mut last_sample = 0;
+    let shifts: &[u8] = if sample_bits == 4 { &[4, 0] } else { &[0] };
+    let mask = ((1u16 << sample_bits) - 1) as u8;
     loop {
         reader.read_exact(&mut buffer).unwrap();
-        for shift in [4u8, 0u8].iter() {
-            let sample = (buffer[0] >> shift) & 0x0f;
+        for shift in shifts.iter() {
+            let sample = (buffer[0] >> shift) & mask;
             let rising = sample & !last_sample;
             let falling = !sample & last_sample;
             callback(rising, falling);
@@ -160,3 +943,192 @@ pub fn sample(command: &str, mut callback: impl FnMut(u8, u8)) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn air(temperature: f64, pressure: f64, humidity: f64, co2: f64) -> AirConditions {
+        AirConditions {
+            temperature: temperature,
+            pressure: pressure,
+            humidity: humidity,
+            co2: co2
+        }
+    }
+
+    #[test]
+    fn ciddor_refractive_index() {
+        // Dry air at 633 nm, 20 °C and 450 ppm CO2 (Ciddor, table 1).
+        let n = air(20.0, 80.0e3, 0.0, 450.0).refractive_index(633.0e-9);
+        assert!(((n - 1.0)*1.0e8 - 21458.0).abs() < 0.5, "{}", n);
+        let n = air(20.0, 100.0e3, 0.0, 450.0).refractive_index(633.0e-9);
+        assert!(((n - 1.0)*1.0e8 - 26824.4).abs() < 0.5, "{}", n);
+        let n = air(20.0, 120.0e3, 0.0, 450.0).refractive_index(633.0e-9);
+        assert!(((n - 1.0)*1.0e8 - 32191.6).abs() < 0.5, "{}", n);
+        // Standard laboratory air.
+        let n = air(20.0, 101325.0, 50.0, 450.0).refractive_index(633.0e-9);
+        assert!(((n - 1.0) - 2.714e-4).abs() < 0.001e-4, "{}", n);
+    }
+
+    #[test]
+    fn compensated_cic_constant_and_ramp() {
+        let position = 1000*Dpll::TURN;
+        let mut cic = CompensatedCic::new(3, 16).unwrap();
+        let outputs: Vec<Vec<i64>> = (0..16*10).filter_map(|_| cic.input(position)).collect();
+        assert_eq!(outputs.len(), 10 - 3 - 2);
+        assert!(outputs.iter().all(|output| output == &vec![position]));
+
+        // The compensation filter is symmetric, so a ramp is only delayed.
+        let mut cic = CompensatedCic::new(3, 16).unwrap();
+        assert_eq!(cic.delay(), 22.5 + 16.0);
+        for i in 0..16*10 {
+            if let Some(output) = cic.input(position + 1000*i) {
+                assert_eq!(output, vec![position + 1000*i - (1000.0*cic.delay()) as i64]);
+            }
+        }
+    }
+
+    #[test]
+    fn moving_average_window() {
+        assert!(MovingAverage::new(0, 2).is_err());
+        assert!(DecimatorConfig::MovingAverage { length: 0 }.create(2).is_err());
+        let mut average = MovingAverage::new(4, 2).unwrap();
+        let outputs: Vec<Vec<i64>> = [10, 20, 30, 40, 50, 60].iter()
+            .filter_map(|&data| average.input(data)).collect();
+        // No output until the window is full.
+        assert_eq!(outputs, vec![vec![25], vec![45]]);
+        assert_eq!(average.delay(), 1.5);
+    }
+
+    #[test]
+    fn block_median_glitch() {
+        let mut median = BlockMedian::new(5);
+        let outputs: Vec<Vec<i64>> = [3, 1000, 2, 4, -7, 5, 5, -5000, 5, 6].iter()
+            .filter_map(|&data| median.input(data)).collect();
+        assert_eq!(outputs, vec![vec![3], vec![5]]);
+        assert_eq!(median.delay(), 2.0);
+    }
+
+    #[test]
+    fn displacement_compensation() {
+        // n - 1 = 26824.4e-8 when zeroed and 21458.0e-8 afterwards (Ciddor, table 1), with
+        // a dead path of 0.1 m and a vacuum displacement of 0.05 m.
+        let (zeroed, measured) = (air(20.0, 100.0e3, 0.0, 450.0), air(20.0, 80.0e3, 0.0, 450.0));
+        let mut compensator = DisplacementCompensator::new(633.0e-9, 0.1, None);
+        assert_eq!(compensator.compensate(Some(&zeroed), 20.0, 0.0), 0.0);
+        let displacement = compensator.compensate(Some(&measured), 20.0, 0.05);
+        assert!((displacement - 0.049994638550).abs() < 5.0e-9, "{}", displacement);
+        // The index at the next compensation becomes the reference.
+        compensator.reset();
+        assert!(compensator.compensate(Some(&measured), 20.0, 0.0).abs() < 1.0e-15);
+
+        // 11.5e-6/K at 25 °C, normalized to 20 °C.
+        let mut compensator = DisplacementCompensator::new(633.0e-9, 0.1, Some(11.5e-6));
+        let displacement = compensator.compensate(None, 25.0, 0.1);
+        assert!((displacement - 0.099994250331).abs() < 1.0e-12, "{}", displacement);
+        assert_eq!(compensator.compensate(None, 20.0, 0.1), 0.1);
+    }
+
+    #[test]
+    fn filter_cascade_cutoff() {
+        let sample_rate = 48.0e3;
+        for design in [FilterDesign::Butterworth { order: 2, cutoff: 1.0e3 },
+                       FilterDesign::Butterworth { order: 4, cutoff: 1.0e3 },
+                       FilterDesign::Bessel { order: 4, cutoff: 1.0e3 }].iter() {
+            let mut filter = FilterCascade::new(design, sample_rate).unwrap();
+            assert!(filter.attenuation(0.0, sample_rate).abs() < 1.0e-9);
+            assert!((filter.attenuation(1.0e3, sample_rate) - 3.01).abs() < 0.05,
+                "{:?}: {}", design, filter.attenuation(1.0e3, sample_rate));
+            let output = (0..10000).fold(0.0, |_, _| filter.run(1.0));
+            assert!((output - 1.0).abs() < 1.0e-9);
+        }
+        assert!(FilterCascade::new(&FilterDesign::Bessel { order: 9, cutoff: 1.0e3 }, sample_rate).is_err());
+    }
+
+    #[test]
+    fn position_tracker_limits() {
+        let mut tracker = PositionTracker::with_limits(Some(Dpll::TURN/2), Some(3*Dpll::TURN));
+        tracker.edge(0);
+        assert_eq!(tracker.edge(Dpll::TURN), Dpll::TURN);
+        assert_eq!(tracker.edge(2*Dpll::TURN + Dpll::TURN/4), 3*Dpll::TURN/4);
+        assert_eq!(tracker.error(), None);
+        tracker.edge(4*Dpll::TURN + Dpll::TURN/4);
+        assert_eq!(tracker.error(), Some(PositionError::Glitch(-Dpll::TURN)));
+        tracker.clear_error();
+        tracker.check(6*Dpll::TURN);
+        assert_eq!(tracker.error(), None);
+        tracker.check(8*Dpll::TURN);
+        assert_eq!(tracker.error(), Some(PositionError::Timeout));
+    }
+
+    #[test]
+    fn cic_constant_and_ramp() {
+        let position = 1000*Dpll::TURN;
+        let mut cic = Cic::new(3, 16).unwrap();
+        let outputs: Vec<i64> = (0..16*10).filter_map(|_| cic.input(position)).collect();
+        assert_eq!(outputs.len(), 10 - 3);
+        assert!(outputs.iter().all(|&output| output == position));
+
+        // The output of a ramp is its value delay() samples before the output.
+        let mut cic = Cic::new(3, 16).unwrap();
+        for i in 0..16*10 {
+            if let Some(output) = cic.input(position + 1000*i) {
+                assert_eq!(output, position + 1000*i - (1000.0*cic.delay()) as i64);
+            }
+        }
+    }
+
+    #[test]
+    fn cic_gain_limit() {
+        assert!(Cic::new(4, 4_800_000).is_err());
+        assert!(DecimatorConfig::Cic { stages: 4, compensation: true }.create(4_800_000).is_err());
+        // At the maximum gain, the output is exact for any input.
+        for &position in [i64::max_value() - 5, i64::min_value() + 5, 1000*Dpll::TURN].iter() {
+            let mut cic = Cic::new(4, 1 << 16).unwrap();
+            let outputs: Vec<i64> = (0..6 << 16).filter_map(|_| cic.input(position)).collect();
+            assert_eq!(outputs, vec![position; 2]);
+        }
+    }
+
+    #[test]
+    fn motion_estimator_ramp() {
+        let sample_rate = 10.0e3;
+        let mut estimator = MotionEstimator::new(100.0, sample_rate).unwrap();
+        // The first position only sets the reference.
+        estimator.input(5000, sample_rate);
+        assert_eq!((estimator.velocity(), estimator.acceleration()), (0.0, 0.0));
+        for i in 1..2000 {
+            estimator.input(5000 + 3*i, sample_rate);
+        }
+        assert!((estimator.velocity() - 3.0*sample_rate).abs() < 1.0e-6, "{}", estimator.velocity());
+        assert!(estimator.acceleration().abs() < 1.0e-3, "{}", estimator.acceleration());
+
+        // Constant acceleration of 2 units per sample^2.
+        let mut estimator = MotionEstimator::new(100.0, sample_rate).unwrap();
+        for i in 0..4000 {
+            estimator.input(i*i, sample_rate);
+        }
+        let acceleration = 2.0*sample_rate*sample_rate;
+        assert!((estimator.acceleration() - acceleration).abs() < 1.0e-6*acceleration, "{}", estimator.acceleration());
+    }
+
+    #[test]
+    fn welch_sine() {
+        // Sine of amplitude 2 at the center of a frequency bin, on a linear trend.
+        let (sample_rate, segment) = (64.0e3, 256);
+        let data: Vec<f64> = (0..16*segment).map(|i| {
+            let t = (i as f64)/sample_rate;
+            2.0*(2.0*std::f64::consts::PI*1.0e3*t).sin() + 5.0 + 100.0*t
+        }).collect();
+        let psd = welch_psd(&data, segment, sample_rate);
+        assert_eq!(psd.len(), segment/2 + 1);
+        let resolution = sample_rate/(segment as f64);
+        let peak = (0..psd.len()).max_by(|&a, &b| psd[a].partial_cmp(&psd[b]).unwrap()).unwrap();
+        assert_eq!(peak, 4);
+        // Mean square of the sine.
+        let power: f64 = psd[1..8].iter().sum::<f64>()*resolution;
+        assert!((power - 2.0).abs() < 0.02, "{}", power);
+        assert!(psd[20..].iter().all(|&p| p < 1.0e-6*psd[peak]));
+    }
+}
@@ -1,27 +1,228 @@
 extern crate num_traits;
 extern crate serde_derive;
 extern crate serde_json;
+extern crate argparse;
 
+use argparse::{ArgumentParser, StoreTrue, Store};
 use serde_derive::Deserialize;
 
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Write};
 use std::path::Path;
+use std::collections::VecDeque;
 
+// Shared with the wavemeter, which uses other parts of it.
+#[allow(dead_code)]
 mod noptica;
 
 #[derive(Deserialize, Debug)]
 struct Config {
     sample_command: String,
     sample_rate: f64,
+    sample_bits: Option<u8>,    // 4 (default) or 8
     bit_ref: u8,
-    bit_meas: u8,
+    bit_meas: Option<u8>,       // Single axis, used if axes is absent
+    // Measurement axes sharing the REF signal. All axes are sampled at each REF edge
+    // so that the output columns are aligned in time.
+    axes: Option<Vec<AxisConfig>>,
+    derived: Option<Vec<DerivedConfig>>, // Additional output columns computed from the axes
     ref_min: f64,
     ref_max: f64,
     refpll_ki: i64,
     refpll_kp: i64,
-    decimation: u32
+    decimation: u32,            // Number of MEAS (bit_meas) or REF (axes) periods per output
+
+    // Output in physical units. If ref_wavelength (vacuum wavelength of the REF laser, in m)
+    // is absent, raw position counts in Dpll::TURN units per REF wavelength are printed.
+    ref_wavelength: Option<f64>,
+    interferometer: Option<Interferometer>, // default: linear
+    unit: Option<LengthUnit>,               // default: um
+    precision: Option<usize>,               // Number of decimals printed (default: 4)
+
+    // Environmental compensation, requires ref_wavelength. If air is present, positions
+    // are corrected for the refractive index of air, and for the change of the optical
+    // length of the dead path (in m) since the start of the measurement. If cte (in 1/K)
+    // is present, positions are normalized to 20 °C using material_temperature (in degrees
+    // Celsius, default: the air temperature).
+    air: Option<noptica::AirConfig>,
+    dead_path: Option<f64>,
+    cte: Option<f64>,
+    material_temperature: Option<f64>,
+
+    // Positions are reported as invalid (NaN) after a displacement between two MEAS edges
+    // that exceeds max_velocity (in m/s, requires ref_wavelength), or after no MEAS edge
+    // was received for meas_timeout (in s). The error stays latched until the "reset",
+    // "zero" or "preset" command is received on the standard input.
+    max_velocity: Option<f64>,
+    meas_timeout: Option<f64>,
+
+    // If present, the velocity and acceleration of each axis (in units per s and per s^2)
+    // are estimated with this bandwidth (in Hz) and printed after the other columns.
+    velocity_bandwidth: Option<f64>,
+
+    // If present, positions are decimated to this fixed rate (in Hz) by a decimator clocked
+    // by the sample clock, instead of decimating by decimation periods. The rate is rounded
+    // to an integer division of sample_rate. Each output line then starts with its time in s.
+    output_rate: Option<f64>,
+    // Default: average, or a CIC filter with 3 stages if output_rate is present. The gain
+    // limit of the CIC filter then requires output_rate to be above about 18 Hz at a
+    // sample_rate of 48 MHz, so low-frequency spectra (e.g. a 1-10 Hz band) are resolved
+    // with long spectrum segments rather than with a low output_rate.
+    decimator: Option<noptica::DecimatorConfig>,
+
+    spectrum: Option<SpectrumConfig>,       // Vibration analysis settings (--spectrum)
+    capture: Option<CaptureConfig>,         // Triggered capture of the position of an axis
+}
+
+#[derive(Deserialize, Debug)]
+struct CaptureConfig {
+    axis: String,           // Axis whose position is captured at each of its MEAS edges
+    trigger: Trigger,
+    pre: usize,             // Number of MEAS edges captured before the trigger
+    post: usize,            // Number of MEAS edges captured after the trigger
+    // File to which each capture is written, with "{n}" replaced by the capture number.
+    // Each line contains the time relative to the trigger in s and the position.
+    file: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Trigger {
+    // The position of the axis crosses the threshold upwards (above) or downwards (below).
+    // The threshold is in the output unit, without environmental compensation.
+    Above(f64),
+    Below(f64),
+    // The magnitude of the velocity of the axis exceeds the limit (in units per s).
+    // Requires velocity_bandwidth.
+    Velocity(f64),
+    // Any change of the given input bit.
+    Bit(u8),
+}
+
+#[derive(Deserialize, Debug)]
+struct SpectrumConfig {
+    segment: usize,         // Length of the Welch segments in samples (power of 2)
+    segments: usize,        // Number of segments averaged, overlapping by half
+    peaks: usize,           // Number of dominant peaks reported
+    bands: Vec<(f64, f64)>, // Frequency bands (in Hz) in which the RMS displacement is reported
+    csv_file: Option<String>, // File to which the amplitude spectral densities are written
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct AxisConfig {
+    name: String,
+    bit: u8,                // Input bit of the MEAS signal of this axis
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum DerivedConfig {
+    // Position of axis a minus position of axis b.
+    Difference { name: String, a: String, b: String },
+    // Angle in urad between the line joining two parallel axes a and b, and the
+    // perpendicular to the axes. Separation is the distance between the axes in m.
+    // Requires ref_wavelength.
+    Angle { name: String, a: String, b: String, separation: f64 },
+}
+
+impl DerivedConfig {
+    fn name(&self) -> &str {
+        match self {
+            DerivedConfig::Difference { name, .. } => name,
+            DerivedConfig::Angle { name, .. } => name,
+        }
+    }
+
+    fn axes(&self) -> (&str, &str) {
+        match self {
+            DerivedConfig::Difference { a, b, .. } => (a, b),
+            DerivedConfig::Angle { a, b, .. } => (a, b),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Interferometer {
+    Linear,                 // Cube-corner reflector, one fringe per half wavelength of displacement.
+    PlaneMirror,            // Plane mirror, one fringe per quarter wavelength of displacement.
+    Passes(u32),            // Custom number of passes of the beam over the displacement.
+}
+
+impl Interferometer {
+    fn passes(&self) -> u32 {
+        match self {
+            Interferometer::Linear => 2,
+            Interferometer::PlaneMirror => 4,
+            Interferometer::Passes(passes) => *passes,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum LengthUnit {
+    Nm,
+    Um,
+    Mm,
+}
+
+impl LengthUnit {
+    fn meters(&self) -> f64 {
+        match self {
+            LengthUnit::Nm => 1.0e-9,
+            LengthUnit::Um => 1.0e-6,
+            LengthUnit::Mm => 1.0e-3,
+        }
+    }
+}
+
+// Converts positions in Dpll::TURN units of optical path to compensated displacements
+// in the configured unit.
+struct PositionScale {
+    meters_per_turn: f64,
+    unit: f64,
+    precision: usize,
+    material_temperature: Option<f64>,
+    compensator: noptica::DisplacementCompensator,
+}
+
+impl PositionScale {
+    fn new(config: &Config, ref_wavelength: f64) -> PositionScale {
+        let passes = config.interferometer.unwrap_or(Interferometer::Linear).passes();
+        let unit = config.unit.unwrap_or(LengthUnit::Um);
+        PositionScale {
+            meters_per_turn: ref_wavelength/(noptica::Dpll::TURN as f64)/(passes as f64),
+            unit: unit.meters(),
+            precision: config.precision.unwrap_or(4),
+            material_temperature: config.material_temperature,
+            compensator: noptica::DisplacementCompensator::new(
+                ref_wavelength, config.dead_path.unwrap_or(0.0), config.cte),
+        }
+    }
+
+    fn meters(&mut self, air: Option<&noptica::AirConditions>, position: i64) -> f64 {
+        let material_temperature = self.material_temperature
+            .or(air.map(|air| air.temperature))
+            .unwrap_or(20.0);
+        self.compensator.compensate(air, material_temperature,
+            (position as f64)*self.meters_per_turn)
+    }
+
+    // Converts a rate of change of the position (e.g. a velocity) in Dpll::TURN units
+    // to m with the same compensation as positions.
+    fn rate_meters(&mut self, air: Option<&noptica::AirConditions>, rate: f64) -> f64 {
+        let material_temperature = self.material_temperature
+            .or(air.map(|air| air.temperature))
+            .unwrap_or(20.0);
+        self.compensator.compensate(air, material_temperature, rate*self.meters_per_turn)
+            - self.compensator.compensate(air, material_temperature, 0.0)
+    }
+
+    fn format(&self, meters: f64) -> String {
+        format!("{:.*}", self.precision, meters/self.unit)
+    }
 }
 
 fn read_config_from_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
@@ -31,24 +232,601 @@ fn read_config_from_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Erro
     Ok(u)
 }
 
+// Reference of the reported positions of an axis. Positions start at zero at the first
+// MEAS edge, and are re-referenced with the "zero" and "preset" commands.
+#[derive(Clone, Copy, Default)]
+struct Origin {
+    position: i64,      // Position (in Dpll::TURN units) that is reported as the preset value
+    preset: f64,        // In the output unit, or in Dpll::TURN units without ref_wavelength
+}
+
+impl Origin {
+    fn meters(&self, position_scale: &mut PositionScale, air: Option<&noptica::AirConditions>,
+            position: i64) -> f64 {
+        position_scale.meters(air, position - self.position) + self.preset*position_scale.unit
+    }
+
+    fn raw(&self, position: i64) -> i64 {
+        position - self.position + (self.preset as i64)
+    }
+}
+
+// Keeps the recent positions of an axis, and captures them around trigger events.
+struct Capture<'a> {
+    config: &'a CaptureConfig,
+    axis: usize,
+    units_per_turn: f64,
+    threshold: i64,
+    buffer: VecDeque<(u64, i64)>,
+    active: bool,
+    trigger_time: Option<u64>,
+    remaining: usize,
+    count: u32,
+}
+
+impl<'a> Capture<'a> {
+    // units_per_turn converts positions to the unit of the thresholds.
+    fn new(config: &'a CaptureConfig, axis: usize, units_per_turn: f64) -> Capture<'a> {
+        Capture {
+            config: config,
+            axis: axis,
+            units_per_turn: units_per_turn,
+            threshold: 0,
+            buffer: VecDeque::with_capacity(config.pre + config.post + 1),
+            // Conditions already met at startup do not trigger.
+            active: true,
+            trigger_time: None,
+            remaining: 0,
+            count: 0,
+        }
+    }
+
+    // Converts the position threshold to Dpll::TURN units for the given origin of the axis.
+    fn set_origin(&mut self, origin: &Origin) {
+        if let Trigger::Above(threshold) | Trigger::Below(threshold) = self.config.trigger {
+            self.threshold = origin.position + ((threshold - origin.preset)/self.units_per_turn) as i64;
+        }
+    }
+
+    // Starts the post-trigger capture, unless a capture is already in progress
+    // or the pre-trigger buffer is not yet full.
+    fn trigger(&mut self, time: u64) {
+        if self.trigger_time.is_none() && self.buffer.len() >= self.config.pre {
+            self.trigger_time = Some(time);
+            self.remaining = self.config.post;
+        }
+    }
+
+    // Triggers when the condition becomes true.
+    fn condition(&mut self, time: u64, active: bool) {
+        if active && !self.active {
+            self.trigger(time);
+        }
+        self.active = active;
+    }
+
+    // Called at each MEAS edge of the axis. Returns the trigger time and the captured
+    // (time, position) pairs when a capture is complete.
+    fn edge(&mut self, time: u64, position: i64) -> Option<(u64, Vec<(u64, i64)>)> {
+        match self.config.trigger {
+            Trigger::Above(_) => self.condition(time, position > self.threshold),
+            Trigger::Below(_) => self.condition(time, position < self.threshold),
+            _ => ()
+        }
+        match self.trigger_time {
+            Some(_) => if self.remaining > 0 {
+                self.buffer.push_back((time, position));
+                self.remaining -= 1;
+            },
+            None => {
+                self.buffer.push_back((time, position));
+                if self.buffer.len() > self.config.pre {
+                    self.buffer.pop_front();
+                }
+            }
+        }
+        match self.trigger_time {
+            Some(trigger_time) if self.remaining == 0 => {
+                self.trigger_time = None;
+                self.count += 1;
+                Some((trigger_time, self.buffer.drain(..).collect()))
+            },
+            _ => None
+        }
+    }
+}
+
+// Accumulates the positions of the axes at the output rate and reports their spectra.
+struct SpectrumAnalyzer<'a> {
+    config: &'a SpectrumConfig,
+    sample_rate: f64,
+    names: Vec<String>,
+    data: Vec<Vec<f64>>,
+}
+
+impl<'a> SpectrumAnalyzer<'a> {
+    fn new(config: &'a SpectrumConfig, sample_rate: f64, names: Vec<String>) -> SpectrumAnalyzer<'a> {
+        if !config.segment.is_power_of_two() || config.segments < 1 {
+            eprintln!("spectrum segment must be a power of 2, with at least one segment");
+            std::process::exit(1);
+        }
+        SpectrumAnalyzer {
+            config: config,
+            sample_rate: sample_rate,
+            data: names.iter().map(|_| Vec::new()).collect(),
+            names: names,
+        }
+    }
+
+    fn reset(&mut self) {
+        for data in self.data.iter_mut() {
+            data.clear();
+        }
+    }
+
+    fn input(&mut self, values: &[f64]) {
+        for (data, &value) in self.data.iter_mut().zip(values.iter()) {
+            data.push(value);
+        }
+        if self.data[0].len() == self.config.segment*(self.config.segments + 1)/2 {
+            self.report();
+            self.reset();
+        }
+    }
+
+    fn report(&self) {
+        let segment = self.config.segment;
+        let resolution = self.sample_rate/(segment as f64);
+        let psds: Vec<Vec<f64>> = self.data.iter()
+            .map(|data| noptica::welch_psd(data, segment, self.sample_rate)).collect();
+        for (name, psd) in self.names.iter().zip(psds.iter()) {
+            let rms = |bins: &mut dyn Iterator<Item=usize>| (bins.map(|k| psd[k]).sum::<f64>()*resolution).sqrt();
+            let mut line = name.clone();
+            for &(low, high) in self.config.bands.iter() {
+                let first = ((low/resolution).ceil() as usize).max(1);
+                let last = ((high/resolution).floor() as usize).min(psd.len() - 1);
+                line += &format!(" rms {}-{} Hz {:.6}", low, high, rms(&mut (first..=last)));
+            }
+            // Amplitudes of the peaks are the RMS over the main lobe of the window.
+            let mut peaks: Vec<usize> = (2..psd.len() - 1)
+                .filter(|&k| psd[k] > psd[k - 1] && psd[k] >= psd[k + 1]).collect();
+            peaks.sort_by(|&a, &b| psd[b].partial_cmp(&psd[a]).unwrap());
+            line += " peaks";
+            for &k in peaks.iter().take(self.config.peaks) {
+                let lobe = (k - 2)..=(k + 2).min(psd.len() - 1);
+                line += &format!(" {:.2} Hz {:.6}", (k as f64)*resolution, rms(&mut lobe.into_iter()));
+            }
+            println!("{}", line);
+        }
+        if let Some(csv_file) = &self.config.csv_file {
+            let result = File::create(csv_file).and_then(|mut file| {
+                writeln!(file, "frequency,{}", self.names.join(","))?;
+                for k in 0..psds[0].len() {
+                    let asds: Vec<String> = psds.iter().map(|psd| format!("{:e}", psd[k].sqrt())).collect();
+                    writeln!(file, "{},{}", (k as f64)*resolution, asds.join(","))?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                eprintln!("failed to write spectrum: {}", e);
+            }
+        }
+    }
+}
+
 fn main() {
-    let config = read_config_from_file("simple-dmi.json").unwrap();
+    let mut spectrum = false;
+    let mut config_file = "simple-dmi.json".to_string();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.refer(&mut spectrum)
+            .add_option(&["--spectrum"], StoreTrue,
+            "Vibration analysis: report spectra instead of positions (requires output_rate)");
+        ap.refer(&mut config_file)
+            .add_option(&["--config"], Store,
+            "Configuration file");
+        ap.parse_args_or_exit();
+    }
+    let config = read_config_from_file(config_file).unwrap();
     let mut refpll = noptica::Dpll::new(
         noptica::Dpll::frequency_to_ftw(config.ref_min, config.sample_rate),
         noptica::Dpll::frequency_to_ftw(config.ref_max, config.sample_rate),
         config.refpll_ki,
         config.refpll_kp);
-    let mut position_tracker = noptica::PositionTracker::new();
-    let mut decimator = noptica::Decimator::new(config.decimation);
-    noptica::sample(&config.sample_command, |rising, _falling| {
+
+    let axes = match (&config.axes, config.bit_meas) {
+        (Some(axes), _) => axes.clone(),
+        (None, Some(bit_meas)) => vec![AxisConfig { name: "position".to_string(), bit: bit_meas }],
+        (None, None) => {
+            eprintln!("no bit_meas or axes configured");
+            std::process::exit(1);
+        }
+    };
+    let axis_index = |name: &str| axes.iter().position(|axis| axis.name == name)
+        .unwrap_or_else(|| { eprintln!("unknown axis: {}", name); std::process::exit(1) });
+    let derived: Vec<(&DerivedConfig, usize, usize)> = config.derived.iter().flatten()
+        .map(|derived| {
+            let (a, b) = derived.axes();
+            (derived, axis_index(a), axis_index(b))
+        }).collect();
+    let decimator = match (config.decimator, config.output_rate) {
+        (Some(decimator), _) => decimator,
+        (None, Some(_)) => noptica::DecimatorConfig::Cic { stages: 3, compensation: false },
+        (None, None) => noptica::DecimatorConfig::Average
+    };
+    let ratio = match config.output_rate {
+        Some(output_rate) => (config.sample_rate/output_rate).round().max(1.0) as u32,
+        None => config.decimation
+    };
+    // Without output_rate, a single bit_meas axis is decimated at its MEAS edges, and
+    // multiple axes at the REF edges so that their columns stay aligned.
+    let decimation_bit = if config.axes.is_none() { axes[0].bit } else { config.bit_ref };
+    let mut decimators: Vec<Box<dyn noptica::Decimate>> = axes.iter()
+        .map(|_| decimator.create(ratio)).collect::<Result<_, _>>()
+        .unwrap_or_else(|e| { eprintln!("invalid decimator: {}", e); std::process::exit(1) });
+    let outputs = decimators[0].outputs();
+    let mut header: Vec<String> = config.output_rate.iter().map(|_| "time".to_string())
+        .chain(axes.iter().flat_map(|axis| outputs.iter().map(move |output| format!("{}{}", axis.name, output))))
+        .chain(derived.iter().map(|(derived, _, _)| derived.name().to_string())).collect();
+    if config.velocity_bandwidth.is_some() {
+        for axis in axes.iter() {
+            header.push(format!("{}.v", axis.name));
+            header.push(format!("{}.a", axis.name));
+        }
+    }
+    eprintln!("# {}", header.join(" "));
+
+    // Displacements are tolerated up to the Doppler shift at max_velocity, plus the
+    // quantization of the REF phase by the sample clock.
+    let max_step = config.max_velocity.map(|max_velocity| {
+        let ref_wavelength = config.ref_wavelength.unwrap_or_else(|| {
+            eprintln!("max_velocity requires ref_wavelength");
+            std::process::exit(1)
+        });
+        let passes = config.interferometer.unwrap_or(Interferometer::Linear).passes();
+        let doppler = max_velocity*(passes as f64)/ref_wavelength;
+        ((noptica::Dpll::TURN as f64)*doppler/config.ref_min) as i64
+            + 2*noptica::Dpll::frequency_to_ftw(config.ref_max, config.sample_rate)
+    });
+    let timeout = config.meas_timeout.map(|meas_timeout|
+        (meas_timeout*config.ref_min*(noptica::Dpll::TURN as f64)) as i64);
+    let mut position_trackers: Vec<noptica::PositionTracker> = axes.iter()
+        .map(|_| noptica::PositionTracker::with_limits(max_step, timeout)).collect();
+    let mut positions = vec![0i64; axes.len()];
+    let mut started = vec![false; axes.len()];
+    let mut origins = vec![Origin::default(); axes.len()];
+    let mut averages: Vec<Vec<i64>> = decimators.iter()
+        .map(|decimator| vec![0; decimator.outputs().len()]).collect();
+    let mut position_scales: Option<Vec<PositionScale>> = config.ref_wavelength
+        .map(|ref_wavelength| axes.iter().map(|_| PositionScale::new(&config, ref_wavelength)).collect());
+    if position_scales.is_none() && (config.air.is_some() || config.cte.is_some()
+            || derived.iter().any(|(derived, _, _)| matches!(derived, DerivedConfig::Angle { .. }))) {
+        eprintln!("environmental compensation and angles require ref_wavelength");
+        std::process::exit(1);
+    }
+    let air_monitor = config.air.as_ref().map(noptica::AirMonitor::new);
+    let commands = noptica::stdin_commands();
+    let mut motion_estimators: Option<Vec<noptica::MotionEstimator>> = config.velocity_bandwidth
+        .map(|bandwidth| axes.iter().map(|_| noptica::MotionEstimator::new(
+            bandwidth, (config.ref_min + config.ref_max)/2.0).unwrap()).collect());
+
+    let mut sample_clock = 0u64;
+    let mut capture = config.capture.as_ref().map(|capture_config| {
+        let axis = axis_index(&capture_config.axis);
+        let units_per_turn = match &position_scales {
+            Some(position_scales) => position_scales[axis].meters_per_turn/position_scales[axis].unit,
+            None => 1.0
+        };
+        if let Trigger::Velocity(_) = capture_config.trigger {
+            if motion_estimators.is_none() {
+                eprintln!("velocity trigger requires velocity_bandwidth");
+                std::process::exit(1);
+            }
+        }
+        Capture::new(capture_config, axis, units_per_turn)
+    });
+    let mut spectrum_analyzer = if spectrum {
+        match (&config.spectrum, config.output_rate) {
+            (Some(spectrum), Some(_)) =>
+                Some(SpectrumAnalyzer::new(spectrum, config.sample_rate/(ratio as f64),
+                    axes.iter().map(|axis| axis.name.clone()).collect())),
+            _ => {
+                eprintln!("spectrum mode requires spectrum and output_rate settings");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    noptica::sample(&config.sample_command, config.sample_bits.unwrap_or(4), |rising, falling| {
+        sample_clock += 1;
         refpll.tick(rising & (1 << config.bit_ref) != 0);
         if refpll.locked() {
-            if rising & (1 << config.bit_meas) != 0 {
-                let position = position_tracker.edge(refpll.get_phase_unwrapped());
-                if let Some(position_avg) = decimator.input(position) {
-                    println!("{}", position_avg);
+            for (i, axis) in axes.iter().enumerate() {
+                if rising & (1 << axis.bit) != 0 {
+                    positions[i] = position_trackers[i].edge(refpll.get_phase_unwrapped());
+                    if !started[i] {
+                        started[i] = true;
+                        origins[i].position = positions[i];
+                        if let Some(capture) = capture.as_mut().filter(|capture| capture.axis == i) {
+                            capture.set_origin(&origins[i]);
+                        }
+                    }
+                    if let Some(capture) = capture.as_mut().filter(|capture| capture.axis == i) {
+                        if let Some((trigger_time, data)) = capture.edge(sample_clock, positions[i]) {
+                            let file_name = capture.config.file.replace("{n}", &capture.count.to_string());
+                            let air = air_monitor.as_ref().map(|air_monitor| air_monitor.get());
+                            let capture_scale = CaptureScale::new(&mut position_scales, air.as_ref(), origins[i], i);
+                            write_capture(file_name, capture_scale, config.sample_rate, trigger_time, data);
+                        }
+                    }
+                }
+            }
+            if let Some(capture) = &mut capture {
+                if let Trigger::Bit(bit) = capture.config.trigger {
+                    if (rising | falling) & (1 << bit) != 0 {
+                        capture.trigger(sample_clock);
+                    }
+                }
+            }
+            // All decimators are fed at the same time and produce their outputs together.
+            let mut output = false;
+            let mut decimate = |decimators: &mut Vec<Box<dyn noptica::Decimate>>| {
+                for (i, decimator) in decimators.iter_mut().enumerate() {
+                    if let Some(values) = decimator.input(positions[i]) {
+                        averages[i] = values;
+                        output = true;
+                    }
+                }
+            };
+            if rising & (1 << config.bit_ref) != 0 {
+                for position_tracker in position_trackers.iter_mut() {
+                    position_tracker.check(refpll.get_phase_unwrapped());
+                }
+                if let Some(motion_estimators) = &mut motion_estimators {
+                    let ref_frequency = refpll.get_frequency(config.sample_rate);
+                    // Each estimator starts at the first MEAS edge of its axis.
+                    for (i, motion_estimator) in motion_estimators.iter_mut().enumerate() {
+                        if started[i] {
+                            motion_estimator.input(positions[i], ref_frequency);
+                        }
+                    }
+                    if let Some(capture) = capture.as_mut().filter(|capture| started[capture.axis]) {
+                        if let Trigger::Velocity(limit) = capture.config.trigger {
+                            let velocity = motion_estimators[capture.axis].velocity()*capture.units_per_turn;
+                            capture.condition(sample_clock, velocity.abs() > limit);
+                        }
+                    }
+                }
+            }
+            // Decimation, and so the output and the spectra, start when all axes have a position.
+            let all_started = started.iter().all(|&started| started);
+            if all_started && (config.output_rate.is_some() || rising & (1 << decimation_bit) != 0) {
+                decimate(&mut decimators);
+            }
+            // Time at the center of the impulse response.
+            let time = config.output_rate
+                .map(|_| ((sample_clock as f64) - decimators[0].delay())/config.sample_rate);
+
+            if output {
+                let air = air_monitor.as_ref().map(|air_monitor| air_monitor.get());
+                for command in commands.try_iter() {
+                    let words: Vec<&str> = command.split_whitespace().collect();
+                    // Commands apply to all axes, or to the axis named after the command.
+                    let (selected, args): (Vec<usize>, &[&str]) = match words.get(1)
+                            .and_then(|name| axes.iter().position(|axis| axis.name == *name)) {
+                        Some(i) => (vec![i], &words[2..]),
+                        None => ((0..axes.len()).collect(), &words[1..])
+                    };
+                    match (words[0], args) {
+                        ("reset", []) => for &i in selected.iter() {
+                            position_trackers[i].clear_error();
+                        },
+                        ("zero", []) | ("preset", [_]) => match args.first().map_or(Ok(0.0), |value| value.parse()) {
+                            Ok(preset) => for &i in selected.iter() {
+                                origins[i] = Origin { position: positions[i], preset: preset };
+                                position_trackers[i].clear_error();
+                                if let Some(position_scales) = &mut position_scales {
+                                    position_scales[i].compensator.reset();
+                                }
+                                if let Some(capture) = capture.as_mut().filter(|capture| capture.axis == i) {
+                                    capture.set_origin(&origins[i]);
+                                }
+                            },
+                            Err(_) => eprintln!("invalid preset value: {}", command)
+                        },
+                        ("latch", []) => {
+                            let values: Vec<String> = selected.iter().map(|&i| {
+                                let value = match (&mut position_scales, position_trackers[i].error()) {
+                                    (_, Some(_)) => "NaN".to_string(),
+                                    (Some(position_scales), None) => {
+                                        let meters = origins[i].meters(&mut position_scales[i], air.as_ref(), positions[i]);
+                                        position_scales[i].format(meters)
+                                    },
+                                    (None, None) => origins[i].raw(positions[i]).to_string()
+                                };
+                                format!("{} {}", axes[i].name, value)
+                            }).collect();
+                            eprintln!("latch {:.6} {}", (sample_clock as f64)/config.sample_rate, values.join(" "));
+                        },
+                        _ => eprintln!("unknown command: {}", command)
+                    }
+                }
+                let valid: Vec<bool> = position_trackers.iter()
+                    .map(|position_tracker| position_tracker.error().is_none()).collect();
+                if let Some(spectrum_analyzer) = &mut spectrum_analyzer {
+                    if valid.iter().all(|&valid| valid) {
+                        let values: Vec<f64> = match &mut position_scales {
+                            Some(position_scales) => position_scales.iter_mut().zip(averages.iter())
+                                .map(|(position_scale, values)| position_scale.meters(air.as_ref(), values[0])/position_scale.unit)
+                                .collect(),
+                            None => averages.iter().map(|values| values[0] as f64).collect()
+                        };
+                        spectrum_analyzer.input(&values);
+                    } else {
+                        spectrum_analyzer.reset();
+                    }
+                    return;
+                }
+                let columns = output_columns(&mut position_scales, air.as_ref(), &derived,
+                    &origins, &averages, &valid, &motion_estimators);
+                match time {
+                    Some(time) => println!("{:.6} {}", time, columns.join(" ")),
+                    None => println!("{}", columns.join(" "))
                 }
             }
         }
     })
 }
+
+// Affine conversion of the positions of an axis to the output unit, evaluated once for
+// a whole capture.
+struct CaptureScale {
+    offset: f64,
+    scale: f64,
+    precision: usize,
+}
+
+impl CaptureScale {
+    fn new(position_scales: &mut Option<Vec<PositionScale>>, air: Option<&noptica::AirConditions>,
+            origin: Origin, axis: usize) -> CaptureScale {
+        match position_scales {
+            Some(position_scales) => {
+                let position_scale = &mut position_scales[axis];
+                CaptureScale {
+                    offset: origin.meters(position_scale, air, 0)/position_scale.unit,
+                    scale: position_scale.rate_meters(air, 1.0)/position_scale.unit,
+                    precision: position_scale.precision
+                }
+            },
+            None => CaptureScale {
+                offset: origin.raw(0) as f64,
+                scale: 1.0,
+                precision: 0
+            }
+        }
+    }
+}
+
+// Writes a capture in a background thread, converting the positions to the output unit.
+fn write_capture(file_name: String, capture_scale: CaptureScale, sample_rate: f64,
+        trigger_time: u64, data: Vec<(u64, i64)>) {
+    eprintln!("writing capture to {}", file_name);
+    std::thread::spawn(move || {
+        let result = File::create(&file_name).and_then(|file| {
+            let mut writer = std::io::BufWriter::new(file);
+            for (time, position) in data {
+                writeln!(writer, "{:.9} {:.*}", ((time as f64) - (trigger_time as f64))/sample_rate,
+                    capture_scale.precision, capture_scale.offset + capture_scale.scale*(position as f64))?;
+            }
+            writer.flush()
+        });
+        if let Err(e) = result {
+            eprintln!("failed to write capture {}: {}", file_name, e);
+        }
+    });
+}
+
+// Formats the averaged positions of the axes and the derived values.
+fn output_columns(position_scales: &mut Option<Vec<PositionScale>>, air: Option<&noptica::AirConditions>,
+        derived: &[(&DerivedConfig, usize, usize)], origins: &[Origin], averages: &[Vec<i64>], valid: &[bool],
+        motion_estimators: &Option<Vec<noptica::MotionEstimator>>) -> Vec<String> {
+    let mut columns: Vec<String> = match position_scales {
+        Some(position_scales) => {
+            let meters: Vec<Vec<f64>> = position_scales.iter_mut().zip(origins.iter())
+                .zip(averages.iter()).zip(valid.iter())
+                .map(|(((position_scale, origin), values), &valid)| values.iter().map(|&value| if valid {
+                    origin.meters(position_scale, air, value)
+                } else {
+                    std::f64::NAN
+                }).collect())
+                .collect();
+            let position_scale = &position_scales[0];
+            meters.iter().flatten().map(|&m| position_scale.format(m))
+                .chain(derived.iter().map(|&(derived, a, b)| match derived {
+                    DerivedConfig::Difference { .. } => position_scale.format(meters[a][0] - meters[b][0]),
+                    DerivedConfig::Angle { separation, .. } => format!("{:.*}",
+                        position_scale.precision, 1.0e6*((meters[a][0] - meters[b][0])/separation).atan()),
+                }))
+                .collect()
+        },
+        None => {
+            let format = |value: i64, valid: bool| if valid { value.to_string() } else { "NaN".to_string() };
+            averages.iter().zip(origins.iter()).zip(valid.iter())
+                .flat_map(|((values, origin), &valid)| values.iter().map(move |&value| format(origin.raw(value), valid)))
+                .chain(derived.iter().map(|&(_, a, b)| format(origins[a].raw(averages[a][0]) - origins[b].raw(averages[b][0]),
+                    valid[a] && valid[b])))
+                .collect()
+        }
+    };
+    for (i, motion_estimator) in motion_estimators.iter().flatten().enumerate() {
+        let rates = [motion_estimator.velocity(), motion_estimator.acceleration()];
+        for &rate in rates.iter() {
+            columns.push(match (&mut *position_scales, valid[i]) {
+                (_, false) => "NaN".to_string(),
+                (Some(position_scales), true) => {
+                    let meters = position_scales[i].rate_meters(air, rate);
+                    position_scales[i].format(meters)
+                },
+                (None, true) => format!("{:.0}", rate)
+            });
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(extra: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{
+            "sample_command": "", "sample_rate": 48e6, "bit_ref": 0,
+            "axes": [{{"name": "a", "bit": 1}}, {{"name": "b", "bit": 2}}],
+            "derived": [{{"difference": {{"name": "d", "a": "a", "b": "b"}}}},
+                        {{"angle": {{"name": "yaw", "a": "a", "b": "b", "separation": 0.05}}}}],
+            "ref_min": 1.9e6, "ref_max": 2.1e6, "refpll_ki": 4294967, "refpll_kp": 85899345,
+            "decimation": 1000 {}
+        }}"#, extra)).unwrap()
+    }
+
+    #[test]
+    fn derived_columns() {
+        let turn = noptica::Dpll::TURN;
+        let config = test_config("");
+        let derived: Vec<(&DerivedConfig, usize, usize)> = config.derived.iter().flatten()
+            .map(|derived| (derived, 0, 1)).collect();
+        let origins = [Origin::default(); 2];
+        // Raw counts, without the angle, which requires ref_wavelength.
+        let columns = output_columns(&mut None, None, &derived[..1], &origins,
+            &[vec![1000], vec![400]], &[true, true], &None);
+        assert_eq!(columns, vec!["1000", "400", "600"]);
+        let columns = output_columns(&mut None, None, &derived[..1], &origins,
+            &[vec![1000], vec![400]], &[true, false], &None);
+        assert_eq!(columns, vec!["1000", "NaN", "NaN"]);
+
+        // With a 1 um reference and a linear interferometer, a turn is 0.5 um. The axes
+        // are 0.5 um apart over a separation of 5 cm, which is an angle of 10 urad.
+        let config = test_config(r#", "ref_wavelength": 1.0e-6"#);
+        let mut position_scales = Some(vec![PositionScale::new(&config, 1.0e-6), PositionScale::new(&config, 1.0e-6)]);
+        let columns = output_columns(&mut position_scales, None, &derived, &origins,
+            &[vec![2*turn], vec![turn]], &[true, true], &None);
+        assert_eq!(columns, vec!["1.0000", "0.5000", "0.5000", "10.0000"]);
+    }
+
+    #[test]
+    fn capture_length() {
+        for &(pre, post) in [(2, 3), (2, 1), (3, 0)].iter() {
+            let config: CaptureConfig = serde_json::from_str(&format!(
+                r#"{{"axis": "x", "trigger": {{"above": 9.5}}, "pre": {}, "post": {}, "file": ""}}"#,
+                pre, post)).unwrap();
+            let mut capture = Capture::new(&config, 0, 1.0);
+            capture.set_origin(&Origin::default());
+            let captures: Vec<(u64, Vec<(u64, i64)>)> = (0..20)
+                .filter_map(|time| capture.edge(time, time as i64)).collect();
+            let times: Vec<u64> = (10 - pre as u64..10 + post as u64).collect();
+            assert_eq!(captures, vec![(10, times.iter().map(|&time| (time, time as i64)).collect())]);
+        }
+    }
+}
@@ -6,31 +6,40 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate biquad;
 
-use argparse::{ArgumentParser, StoreTrue, Store};
-use serde_derive::Deserialize;
-use biquad::Biquad;
+use argparse::{ArgumentParser, StoreTrue, Store, StoreOption};
+use serde_derive::{Deserialize, Serialize};
 
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Write};
 use std::path::Path;
 use std::ops::{Generator, GeneratorState};
 use std::pin::Pin;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::mpsc;
 
+// Shared with simple-dmi, which uses other parts of it.
+#[allow(dead_code)]
 mod noptica;
+mod lock;
 
 #[derive(Deserialize, Debug)]
 struct Config {
     sample_command: String, // Shell command to start the logic analyzer.
     sample_rate: f64,       // Sample rate of the logic analyzer in Hz.
+    sample_bits: Option<u8>, // Bits per sample, 4 (default) or 8.
 
-    // The logic analyzer command must produce a stream of 4-bit nibbles on its
-    // standard output, which are continuously sampled at the nominal sample rate.
-    // Each of the signals below are mapped to one bit within each nibble.
+    // The logic analyzer command must produce a stream of 4-bit nibbles (or bytes, with
+    // 8-bit samples) on its standard output, which are continuously sampled at the nominal
+    // sample rate. Each of the signals below are mapped to one bit within each sample.
     bit_ref: u8,            // Bit# for REF signal of the reference laser head (HP 5501B).
     bit_meas: u8,           // Bit# for displacement measurement detector (HP 10780).
-    bit_input: u8,          // Bit# for input laser interference detector.
+    // Input laser interference detectors, measured simultaneously. Alternatively, bit_input
+    // gives the bit# of a single unnamed input, whose output lines have no name prefix.
+    #[serde(default)]
+    inputs: Vec<InputConfig>,
+    bit_input: Option<u8>,
 
     // The REF DPLL locks to the REF output of the reference laser and provides REF phase
     // information at each sample of the logic analyzer.
@@ -40,23 +49,229 @@ struct Config {
     refpll_ki: i64,         // Integration constant of the DPLL loop filter.
     refpll_kp: i64,         // Proportionality constant of the DPLL loop filter.
 
-    ref_wavelength: f64,    // Wavelength of the reference laser in m.
+    ref_wavelength: f64,    // Wavelength of the reference laser in m (vacuum).
+    // File containing the correction determined with --calibrate-wavelength.
+    // It is applied automatically when it exists.
+    calibration_file: Option<String>,
+
+    // Ambient air conditions, used to correct the measured wavelength for the dispersion of air
+    // between the reference and input wavelengths. The output is then a vacuum wavelength.
+    // No correction is applied if absent.
+    air: Option<noptica::AirConfig>,
 
     position_mon_time: f64, // The time during which position is monitored to compute min/max
     duty_cycle: f64,        // Fraction of the scan used for counting input laser fringes
+    // If present, the turning points of the motion are found from the direction of the
+    // filtered position, which must move by this amount (in nm of optical path) past an
+    // extremum for a turn to be detected. The counting window is then set from the extent
+    // of each individual stroke instead of the min/max over position_mon_time, and each
+    // scan is measured on its own rather than against the limits of the first scan of a block.
+    turnaround_hysteresis: Option<f64>,
+    // If present, fringes are counted within this fixed window instead of a window derived
+    // from the extent of the motion. Can be changed at runtime with the "window" and "home"
+    // commands.
+    window: Option<WindowConfig>,
+    // Tuning of the quadrant tracking. If absent, transitions happen at the limits, and
+    // invalid transitions are only reported.
+    quadrant: Option<QuadrantConfig>,
 
     debug: bool,            // Enable debug output of wavelength determination code
     motion_cutoff: f64,     // Cut-off frequency of the motion filter
+    // Design of the motion filter. Defaults to a second-order Butterworth low-pass filter
+    // with motion_cutoff as cut-off frequency.
+    motion_filter: Option<noptica::FilterDesign>,
+    // Maximum optical path velocity of the motion in m/s (see --calibrate). If present, the
+    // motion filter information includes the attenuation over the resulting MEAS band.
+    max_velocity: Option<f64>,
+    // Position assigned to each input laser fringe (default: hold).
+    fringe_position: Option<FringePosition>,
+    // If present, each input is also measured using this method for assigning fringe
+    // positions, and output separately under the name "<input>/<method>" (or "<method>" for
+    // an unnamed input), for comparison.
+    fringe_position_compare: Option<FringePosition>,
     min_fringes: u32,       // Minimum number of fringes to count
     fringe_jitter_tol: f64, // Tolerance for fringe distance jitter
     decimation: u32,        // Decimation/averaging factor for the final wavelength output
+    rejection: Option<Rejection>, // Rejection of outlier scans before averaging (none if absent)
+
+    // If present, a sliding average is printed after every accepted scan instead of
+    // the average of each block of decimation scans.
+    sliding_window: Option<SlidingWindow>,
+    // If present, the wavelengths of the accepted scans are decimated by decimation with
+    // this filter instead of being averaged in blocks. Each output then contains the
+    // decimated values followed by the number of failed scans.
+    decimator: Option<noptica::DecimatorConfig>,
+
+    // The Allan deviation of the per-scan wavelengths is computed for averaging times of
+    // 1, 2, 4, ... scans. It is printed on the standard error when "allan" is entered on the
+    // standard input, and, if allan_file is present, written to that file at each output.
+    // Any "{name}" in allan_file is replaced with the name of the input.
+    allan_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct InputConfig {
+    name: String,           // Name of the input laser, printed at the start of each output line.
+    bit: u8,                // Bit# for the input laser interference detector.
+    // Nominal vacuum wavelength in m, used to report the fringe rate in calibration mode.
+    wavelength: Option<f64>,
+
+    // If present, the wavelength of this input is locked to a setpoint by a PID controller
+    // acting on the laser. The lock error (nm), control value and lock state are appended
+    // to each output line.
+    lock: Option<lock::LockConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Rejection {
+    // Iteratively discard scans further than the given number of standard deviations
+    // from the mean.
+    SigmaClip(f64),
+    // Discard scans further than the given number of (normal-scaled) median absolute
+    // deviations from the median.
+    Mad(f64),
+}
+
+#[derive(Deserialize, Debug)]
+struct QuadrantConfig {
+    hysteresis: f64,        // Hysteresis around the limits, in nm of optical path
+    min_dwell: f64,         // Minimum time spent in a quadrant before a transition, in s
+    // Number of invalid transitions without a complete scan in between after which
+    // the tracker resynchronizes to the motion (0 to disable).
+    resync_after: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+struct WindowConfig {
+    start: f64,             // Start of the counting window, in um of optical path
+    end: f64,               // End of the counting window, in um of optical path
+    // If present, start and end are relative to this position (in um of optical path).
+    // Otherwise, they are absolute positions of the DMI, which is zeroed at the first
+    // MEAS edge after the reference PLL locks.
+    home: Option<f64>,
+}
+
+impl WindowConfig {
+    // Returns the limits of the window in Dpll::TURN units.
+    fn limits(&self, config: &Config) -> (i64, i64) {
+        let home = self.home.unwrap_or(0.0);
+        let to_turns = |um: f64| (um*1.0e-6/config.ref_wavelength*(noptica::Dpll::TURN as f64)) as i64;
+        let (start, end) = (to_turns(home + self.start), to_turns(home + self.end));
+        (start.min(end), start.max(end))
+    }
+}
+
+// Zero of the DMI positions, taken at the first MEAS edge after the reference PLL locks
+// and again after each loss of lock.
+struct DmiOrigin {
+    origin: Option<i64>,
+}
+
+impl DmiOrigin {
+    pub fn new() -> DmiOrigin {
+        DmiOrigin {
+            origin: None
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.origin = None;
+    }
+
+    // Returns the position measured at a MEAS edge relative to the origin.
+    pub fn position(&mut self, position: i64) -> i64 {
+        position - *self.origin.get_or_insert(position)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FringePosition {
+    // Last position measured at a MEAS edge, as the fringe is detected.
+    Hold,
+    // Position from the motion filter, with the fringe delayed by the group delay of the
+    // filter so that the fringe position and the quadrant tracking refer to the same time.
+    Delayed,
+    // Position linearly interpolated at the time of the fringe between the surrounding
+    // MEAS edges.
+    Linear,
+    // Position at the time of the fringe from a quadratic least-squares fit to the given
+    // number of MEAS edges on each side.
+    Polynomial(usize),
+    // Position from a periodic model of the motion with harmonics, fitted for each scan to
+    // the MEAS edges over one period of the motion, with the period measured between the
+    // starts of the scans. Scans with a RMS fit residual (in nm) above max_residual are
+    // rejected.
+    Model { harmonics: usize, max_residual: f64 },
+}
+
+impl FringePosition {
+    fn name(&self) -> String {
+        match self {
+            FringePosition::Hold => "hold".to_string(),
+            FringePosition::Delayed => "delayed".to_string(),
+            FringePosition::Linear => "linear".to_string(),
+            FringePosition::Polynomial(n) => format!("polynomial{}", n),
+            FringePosition::Model { harmonics, .. } => format!("model{}", harmonics),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum SlidingWindow {
+    Scans(usize),           // Average over the given number of last accepted scans.
+    Time(f64),              // Average over the scans accepted during the given time in s.
 }
 
 fn read_config_from_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
     let file = File::open(path)?;
     let reader = BufReader::new(file);
-    let u = serde_json::from_reader(reader)?;
-    Ok(u)
+    let mut config: Config = serde_json::from_reader(reader)?;
+    if let Some(bit_input) = config.bit_input {
+        if !config.inputs.is_empty() {
+            return Err("bit_input and inputs cannot be used together".into());
+        }
+        config.inputs.push(InputConfig {
+            name: String::new(),
+            bit: bit_input,
+            wavelength: None,
+            lock: None,
+        });
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), String> {
+        if self.inputs.is_empty() {
+            return Err("no inputs configured".to_string());
+        }
+        if self.decimation == 0 {
+            return Err("decimation must be at least 1".to_string());
+        }
+        for fringe_position in self.fringe_position.iter().chain(self.fringe_position_compare.iter()) {
+            if let FringePosition::Polynomial(n) = fringe_position {
+                if *n < 2 {
+                    return Err("polynomial fringe positions require at least 2 MEAS edges on each side".to_string());
+                }
+            }
+        }
+        if let Some(decimator) = &self.decimator {
+            if self.sliding_window.is_some() || self.rejection.is_some() {
+                return Err("decimator cannot be used with sliding_window or rejection".to_string());
+            }
+            decimator.create(self.decimation).map_err(|e| format!("invalid decimator: {}", e))?;
+        }
+        match self.sliding_window {
+            Some(SlidingWindow::Scans(0)) => return Err("sliding window must contain at least one scan".to_string()),
+            Some(SlidingWindow::Time(t)) if !(t > 0.0) => return Err("sliding window duration must be positive".to_string()),
+            _ => ()
+        }
+        Ok(())
+    }
 }
 
 struct MinMaxMonitor {
@@ -97,6 +312,93 @@ impl MinMaxMonitor {
     }
 }
 
+// Nominal rate at which the position is resampled for the motion analysis in calibration
+// mode. The actual rate is the sample rate divided by an integer.
+const MOTION_ANALYSIS_RATE: f64 = 100e3;
+// Number of harmonics of the motion frequency that are analyzed (including the fundamental).
+const MOTION_HARMONICS: usize = 5;
+
+struct MotionReport {
+    amplitude: f64,         // Peak-to-peak optical path displacement in m.
+    frequency: f64,         // Motion frequency in Hz.
+    up_fraction: f64,       // Fraction of the time during which the position increases.
+    peak_velocity: f64,     // Maximum optical path velocity in m/s.
+    // Min/max optical path velocity within the counting window, if any samples fall in it.
+    window_velocity: Option<(f64, f64)>,
+    harmonics: Vec<f64>,    // Amplitudes of the harmonics 2.. relative to the fundamental.
+}
+
+// Analyzes positions resampled at the given rate in Hz.
+fn analyze_motion(config: &Config, rate: f64, positions: &[i64]) -> Option<MotionReport> {
+    let scale = config.ref_wavelength/(noptica::Dpll::TURN as f64);
+    let min = *positions.iter().min()?;
+    let max = *positions.iter().max()?;
+    let middle = (min + max)/2;
+    let hysteresis = (max - min)/10;
+    let off_duty = (((max - min) as f64)*(1.0 - config.duty_cycle)) as i64;
+
+    // Frequency from the upward crossings of the middle position.
+    let mut crossings = Vec::new();
+    let mut above = positions[0] > middle;
+    for (i, &position) in positions.iter().enumerate() {
+        if !above && position > middle + hysteresis {
+            above = true;
+            crossings.push(i);
+        }
+        if above && position < middle - hysteresis {
+            above = false;
+        }
+    }
+    if crossings.len() < 2 {
+        return None;
+    }
+    let period = ((crossings[crossings.len() - 1] - crossings[0]) as f64)/((crossings.len() - 1) as f64);
+    let frequency = rate/period;
+
+    let mut up = 0;
+    let mut down = 0;
+    let mut peak_velocity = 0.0f64;
+    let mut window_velocity: Option<(f64, f64)> = None;
+    for pair in positions.windows(2) {
+        let velocity = ((pair[1] - pair[0]) as f64)*scale*rate;
+        if velocity > 0.0 {
+            up += 1;
+        }
+        if velocity < 0.0 {
+            down += 1;
+        }
+        peak_velocity = peak_velocity.max(velocity.abs());
+        if pair[0] > min + off_duty/2 && pair[0] < max - off_duty/2 {
+            let (low, high) = window_velocity.unwrap_or((velocity.abs(), velocity.abs()));
+            window_velocity = Some((low.min(velocity.abs()), high.max(velocity.abs())));
+        }
+    }
+
+    // Harmonic content over an integer number of periods, with a Hann window.
+    let samples = &positions[crossings[0]..crossings[crossings.len() - 1]];
+    let n = samples.len() as f64;
+    let mut amplitudes = Vec::new();
+    for k in 1..=MOTION_HARMONICS {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &position) in samples.iter().enumerate() {
+            let x = ((position - middle) as f64)*(1.0 - (2.0*std::f64::consts::PI*(i as f64)/n).cos());
+            let phase = 2.0*std::f64::consts::PI*(k as f64)*(i as f64)/period;
+            re += x*phase.cos();
+            im -= x*phase.sin();
+        }
+        amplitudes.push((re*re + im*im).sqrt());
+    }
+
+    Some(MotionReport {
+        amplitude: ((max - min) as f64)*scale,
+        frequency: frequency,
+        up_fraction: (up as f64)/((up + down) as f64),
+        peak_velocity: peak_velocity,
+        window_velocity: window_velocity,
+        harmonics: amplitudes[1..].iter().map(|a| a/amplitudes[0]).collect(),
+    })
+}
+
 fn do_calibrate(config: &Config) {
     let mut refpll = noptica::Dpll::new(
         noptica::Dpll::frequency_to_ftw(config.ref_min, config.sample_rate),
@@ -104,21 +406,56 @@ fn do_calibrate(config: &Config) {
         config.refpll_ki,
         config.refpll_kp);
     let mut position_tracker = noptica::PositionTracker::new();
-    let mut min_max_monitor = MinMaxMonitor::new(
-        ((config.ref_min + config.ref_max)/2.0*config.position_mon_time) as u32);
+    let mut position = 0;
+    let resample_interval = ((config.sample_rate/MOTION_ANALYSIS_RATE) as u32).max(1);
+    let analysis_rate = config.sample_rate/(resample_interval as f64);
+    let mut resample_count = 0;
+    let mut positions = Vec::new();
+    let analysis_length = (analysis_rate*config.position_mon_time) as usize;
 
-    noptica::sample(&config.sample_command, |rising, _falling| {
+    noptica::sample(&config.sample_command, config.sample_bits.unwrap_or(4), |rising, _falling| {
         refpll.tick(rising & (1 << config.bit_ref) != 0);
         if refpll.locked() {
             if rising & (1 << config.bit_meas) != 0 {
-                let position = position_tracker.edge(refpll.get_phase_unwrapped());
-                min_max_monitor.input(position, |min, max| {
-                    let displacement = ((max - min) as f64)/(noptica::Dpll::TURN as f64)*config.ref_wavelength;
-                    println!("{:.1} um", 1.0e6*displacement);
-                });
+                position = position_tracker.edge(refpll.get_phase_unwrapped());
+            }
+            resample_count += 1;
+            if resample_count == resample_interval {
+                resample_count = 0;
+                positions.push(position);
+                if positions.len() == analysis_length {
+                    match analyze_motion(config, analysis_rate, &positions) {
+                        Some(report) => {
+                            let harmonics: Vec<String> = report.harmonics.iter()
+                                .map(|h| format!("{:.1}", 20.0*h.log10())).collect();
+                            let mut line = format!("{:.1} um, {:.2} Hz, up/down {:.1}%/{:.1}%, peak velocity {:.4} m/s",
+                                1.0e6*report.amplitude, report.frequency,
+                                100.0*report.up_fraction, 100.0*(1.0 - report.up_fraction),
+                                report.peak_velocity);
+                            match report.window_velocity {
+                                Some((low, high)) => {
+                                    line += &format!(", window velocity {:.4}-{:.4} m/s", low, high);
+                                    // Input fringes occur once per wavelength of optical path.
+                                    for input in config.inputs.iter() {
+                                        if let Some(wavelength) = input.wavelength {
+                                            line += &format!(", fringe rate {}{:.3}-{:.3} MHz",
+                                                if input.name.is_empty() { String::new() } else { format!("{} ", input.name) },
+                                                1.0e-6*low/wavelength, 1.0e-6*high/wavelength);
+                                        }
+                                    }
+                                },
+                                None => line += ", window velocity n/a"
+                            }
+                            println!("{}, harmonics {} dBc", line, harmonics.join(" "));
+                        },
+                        None => eprintln!("no periodic motion detected")
+                    }
+                    positions.clear();
+                }
             }
         } else {
-            min_max_monitor.reset();
+            position = 0;
+            positions.clear();
         }
     })
 }
@@ -141,10 +478,27 @@ struct QuadrantTracker {
     new_max: i64,
     prev_above_middle: bool,
     middle: i64,
+
+    hysteresis: i64,
+    min_dwell: u32,
+    resync_after: u32,
+    dwell: u32,
+    resyncing: bool,
+    burst_invalid: u32,
+    invalid_transitions: u64,
+    resyncs: u64,
 }
 
 impl QuadrantTracker {
     pub fn new() -> QuadrantTracker {
+        QuadrantTracker::with_tuning(0, 0, 0)
+    }
+
+    // Transitions require the position to cross back over a limit by more than hysteresis,
+    // and to have stayed in the current quadrant for at least min_dwell samples.
+    // After resync_after invalid transitions without a complete scan in between, the
+    // tracker resynchronizes to the position (never if 0).
+    pub fn with_tuning(hysteresis: i64, min_dwell: u32, resync_after: u32) -> QuadrantTracker {
         QuadrantTracker {
             prev_state: Quadrant::BelowMin,
             state: Quadrant::BelowMin,
@@ -154,17 +508,42 @@ impl QuadrantTracker {
             new_max: i64::min_value(),
             prev_above_middle: false,
             middle: i64::max_value(),
+
+            hysteresis: hysteresis,
+            min_dwell: min_dwell,
+            resync_after: resync_after,
+            dwell: 0,
+            resyncing: false,
+            burst_invalid: 0,
+            invalid_transitions: 0,
+            resyncs: 0,
         }
     }
 
+    // Resets the tracking state, keeping the tuning and the statistics.
     pub fn reset(&mut self) {
-        *self = QuadrantTracker::new();
+        let mut tracker = QuadrantTracker::with_tuning(self.hysteresis, self.min_dwell, self.resync_after);
+        tracker.invalid_transitions = self.invalid_transitions;
+        tracker.resyncs = self.resyncs;
+        *self = tracker;
     }
 
     pub fn input(&mut self, position: i64) {
-        let above_min = position > self.min;  // always false before init
-        let below_max = position < self.max;  // always false before init
-        let next_state;
+        // The limit through which the current quadrant was entered is moved back by
+        // the hysteresis, so that noise around it does not cause chatter.
+        let min = match self.state {
+            Quadrant::BelowMin => self.min.saturating_add(self.hysteresis),
+            Quadrant::Up => self.min.saturating_sub(self.hysteresis),
+            _ => self.min
+        };
+        let max = match self.state {
+            Quadrant::AboveMax => self.max.saturating_sub(self.hysteresis),
+            Quadrant::Down => self.max.saturating_add(self.hysteresis),
+            _ => self.max
+        };
+        let above_min = position > min;  // always false before init
+        let below_max = position < max;  // always false before init
+        let mut next_state;
         if above_min && below_max {
             next_state = match self.state {
                 Quadrant::BelowMin => Quadrant::Up,
@@ -179,20 +558,43 @@ impl QuadrantTracker {
                 next_state = Quadrant::BelowMin;  // stays here before init
             }
         }
+        if self.dwell < self.min_dwell {
+            next_state = self.state;
+        }
 
         self.prev_state = self.state;
         if self.state != next_state {
             match (self.state, next_state) {
                 (Quadrant::BelowMin, Quadrant::Up) => (),
-                (Quadrant::Up, Quadrant::AboveMax) => (),
+                (Quadrant::Up, Quadrant::AboveMax) => self.burst_invalid = 0,
                 (Quadrant::AboveMax, Quadrant::Down) => (),
                 (Quadrant::Down, Quadrant::BelowMin) => (),
-                _ => eprintln!("invalid quadrant transition: {:?} -> {:?}",
-                    self.state, next_state)
+                _ if self.resyncing => (),
+                _ => {
+                    eprintln!("invalid quadrant transition: {:?} -> {:?}",
+                        self.state, next_state);
+                    self.invalid_transitions += 1;
+                    self.burst_invalid += 1;
+                }
+            }
+            if next_state == Quadrant::BelowMin {
+                self.resyncing = false;
+            }
+            if self.resyncing {
+                // Do not start a scan before the motion has been seen below min.
+                self.prev_state = next_state;
             }
             self.state = next_state;
+            self.dwell = 0;
         }
+        self.dwell = self.dwell.saturating_add(1);
 
+        if self.resync_after != 0 && self.burst_invalid >= self.resync_after {
+            eprintln!("resynchronizing quadrant tracker after {} invalid transitions", self.burst_invalid);
+            self.burst_invalid = 0;
+            self.resyncs += 1;
+            self.resyncing = self.state != Quadrant::BelowMin;
+        }
 
         // Update min and max when the position is near the middle
         // to avoid glitches.
@@ -204,6 +606,14 @@ impl QuadrantTracker {
         self.prev_above_middle = above_middle;
     }
 
+    pub fn invalid_transitions(&self) -> u64 {
+        self.invalid_transitions
+    }
+
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs
+    }
+
     pub fn update_limits(&mut self, min: i64, max: i64) {
         self.new_min = min;
         self.new_max = max;
@@ -217,71 +627,1031 @@ impl QuadrantTracker {
     pub fn up_end(&self) -> bool {
         self.prev_state == Quadrant::Up && self.state == Quadrant::AboveMax
     }
+}
 
-    pub fn down_start(&self) -> bool {
-        self.prev_state == Quadrant::AboveMax && self.state == Quadrant::Down
+// An upward stroke between two turning points, given as (time, position).
+#[derive(Debug, Clone, Copy)]
+struct Stroke {
+    start: (u64, i64),
+    end: (u64, i64),
+}
+
+// Finds the turning points of the motion from changes in the direction of the filtered
+// position, with hysteresis, and reports each completed upward stroke.
+struct TurnaroundTracker {
+    hysteresis: i64,
+    started: bool,
+    rising: bool,
+    extremum: (u64, i64),
+    top_seen: bool,
+    bottom: Option<(u64, i64)>,
+}
+
+impl TurnaroundTracker {
+    pub fn new(hysteresis: i64) -> TurnaroundTracker {
+        TurnaroundTracker {
+            hysteresis: hysteresis,
+            started: false,
+            rising: false,
+            extremum: (0, 0),
+            top_seen: false,
+            bottom: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = TurnaroundTracker::new(self.hysteresis);
     }
 
-    pub fn down_end(&self) -> bool {
-        self.prev_state == Quadrant::Down && self.state == Quadrant::BelowMin
+    // Time before which no stroke reported in the future can start.
+    pub fn horizon(&self) -> u64 {
+        match self.bottom {
+            Some(bottom) => bottom.0,
+            None => self.extremum.0
+        }
+    }
+
+    pub fn input(&mut self, time: u64, position: i64) -> Option<Stroke> {
+        if !self.started {
+            self.started = true;
+            self.extremum = (time, position);
+            return None;
+        }
+        if self.rising {
+            if position > self.extremum.1 {
+                self.extremum = (time, position);
+            } else if position < self.extremum.1 - self.hysteresis {
+                let top = self.extremum;
+                self.rising = false;
+                self.extremum = (time, position);
+                self.top_seen = true;
+                return self.bottom.take().map(|bottom| Stroke { start: bottom, end: top });
+            }
+        } else {
+            if position < self.extremum.1 {
+                self.extremum = (time, position);
+            } else if position > self.extremum.1 + self.hysteresis {
+                // The first minimum may be the start of the data rather than a turning point.
+                if self.top_seen {
+                    self.bottom = Some(self.extremum);
+                }
+                self.rising = true;
+                self.extremum = (time, position);
+            }
+        }
+        None
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+// Holds the fringes of a channel until the extent of the stroke they belong to is known,
+// and the completed strokes until the positions of all their fringes are known.
+struct StrokeBuffer {
+    fringes: VecDeque<(u64, i64)>,
+    strokes: VecDeque<Stroke>,
+}
+
+impl StrokeBuffer {
+    pub fn new() -> StrokeBuffer {
+        StrokeBuffer {
+            fringes: VecDeque::new(),
+            strokes: VecDeque::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.fringes.clear();
+        self.strokes.clear();
+    }
+
+    pub fn push(&mut self, time: u64, position: i64) {
+        self.fringes.push_back((time, position));
+    }
+
+    pub fn stroke(&mut self, stroke: Stroke) {
+        self.strokes.push_back(stroke);
+    }
+
+    // Discards the fringes that precede the given time and the completed strokes.
+    pub fn discard_before(&mut self, horizon: u64) {
+        let horizon = self.strokes.front().map_or(horizon, |stroke| stroke.start.0.min(horizon));
+        while self.fringes.front().is_some_and(|&(time, _)| time < horizon) {
+            self.fringes.pop_front();
+        }
+    }
+
+    // Emits the scans of the completed strokes that end before the given time of the earliest
+    // unresolved fringe (if any).
+    pub fn flush(&mut self, unresolved: Option<u64>, duty_cycle: f64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        while let Some(stroke) = self.strokes.front().cloned() {
+            if unresolved.is_some_and(|time| time <= stroke.end.0) {
+                break;
+            }
+            self.strokes.pop_front();
+            self.flush_stroke(&stroke, duty_cycle, &mut emit);
+        }
+    }
+
+    // Emits the scan of a stroke, with the fringes within the central duty_cycle fraction
+    // of its extent, and discards the buffered fringes up to its end.
+    fn flush_stroke(&mut self, stroke: &Stroke, duty_cycle: f64, emit: &mut impl FnMut(u64, FringeCounterEvent)) {
+        let off_duty = (((stroke.end.1 - stroke.start.1) as f64)*(1.0 - duty_cycle)) as i64;
+        let low = stroke.start.1 + off_duty/2;
+        let high = stroke.end.1 - off_duty/2;
+        emit(stroke.start.0, FringeCounterEvent::Start);
+        while let Some(&(time, position)) = self.fringes.front() {
+            if time > stroke.end.0 {
+                break;
+            }
+            self.fringes.pop_front();
+            if time >= stroke.start.0 && position > low && position < high {
+                emit(time, FringeCounterEvent::Fringe(position));
+            }
+        }
+        emit(stroke.end.0, FringeCounterEvent::End);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum FringeCounterEvent {
     Start,
     Fringe(i64),
     End,
 }
 
+fn mean_std(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>()/n;
+    let variance = if values.len() > 1 {
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()/(n - 1.0)
+    } else {
+        0.0
+    };
+    (mean, variance.sqrt())
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = sorted.len();
+    if n % 2 == 0 {
+        (sorted[n/2 - 1] + sorted[n/2])/2.0
+    } else {
+        sorted[n/2]
+    }
+}
+
+// Removes outliers from (time, value) pairs.
+fn reject_outliers(scans: &mut Vec<(u64, f64)>, rejection: &Rejection) {
+    match *rejection {
+        Rejection::SigmaClip(threshold) => {
+            while scans.len() > 2 {
+                let values: Vec<f64> = scans.iter().map(|&(_, v)| v).collect();
+                let (mean, std) = mean_std(&values);
+                let count = scans.len();
+                scans.retain(|&(_, v)| (v - mean).abs() <= threshold*std);
+                if scans.len() == count {
+                    break;
+                }
+            }
+        },
+        Rejection::Mad(threshold) => {
+            if scans.len() > 2 {
+                let values: Vec<f64> = scans.iter().map(|&(_, v)| v).collect();
+                let center = median(&values);
+                let deviations: Vec<f64> = values.iter().map(|v| (v - center).abs()).collect();
+                let mad = 1.4826*median(&deviations);
+                scans.retain(|&(_, v)| (v - center).abs() <= threshold*mad);
+            }
+        }
+    }
+}
+
+// Number of octaves of averaging times of the Allan deviation, which bounds the history
+// kept to 2^ALLAN_MAX_OCTAVES scans.
+const ALLAN_MAX_OCTAVES: usize = 18;
+
+// Overlapping Allan deviation, updated incrementally at each input so that it can
+// run for hours with a bounded history. The scans are split into segments of evenly
+// spaced scans at each gap, and the differences of all segments are accumulated.
+struct AllanDeviation {
+    cumsum: VecDeque<f64>,
+    reference: f64,
+    last_time: Option<u64>,
+    min_interval: Option<u64>,
+    interval_sum: u64,
+    interval_count: u64,
+    acc: Vec<f64>,
+    count: Vec<u64>,
+}
+
+impl AllanDeviation {
+    pub fn new() -> AllanDeviation {
+        AllanDeviation {
+            cumsum: VecDeque::from(vec![0.0]),
+            reference: 0.0,
+            last_time: None,
+            min_interval: None,
+            interval_sum: 0,
+            interval_count: 0,
+            acc: Vec::new(),
+            count: Vec::new(),
+        }
+    }
+
+    // Starts a new segment, e.g. after an invalid scan.
+    pub fn gap(&mut self) {
+        self.cumsum.clear();
+        self.cumsum.push_back(0.0);
+        self.last_time = None;
+    }
+
+    pub fn input(&mut self, time: u64, value: f64) {
+        // Scans missing without a notification of the gap are detected from the interval
+        // to the previous scan.
+        if let Some(last_time) = self.last_time {
+            let interval = time - last_time;
+            if self.min_interval.is_some_and(|min_interval| 2*interval > 3*min_interval) {
+                self.gap();
+            } else {
+                self.interval_sum += interval;
+                self.interval_count += 1;
+            }
+            self.min_interval = Some(self.min_interval.map_or(interval, |min_interval| min_interval.min(interval)));
+        }
+        self.last_time = Some(time);
+        // Values are taken relative to the first one of the segment to preserve precision
+        // in the sums.
+        if self.cumsum.len() == 1 {
+            self.reference = value;
+        }
+        let last = *self.cumsum.back().unwrap();
+        self.cumsum.push_back(last + value - self.reference);
+        if self.cumsum.len() > (1 << ALLAN_MAX_OCTAVES) + 1 {
+            self.cumsum.pop_front();
+        }
+
+        let n = self.cumsum.len() - 1;
+        let mut octave = 0;
+        while 2 << octave <= n {
+            let m = 1 << octave;
+            if self.acc.len() == octave {
+                self.acc.push(0.0);
+                self.count.push(0);
+            }
+            let diff = self.cumsum[n] - 2.0*self.cumsum[n - m] + self.cumsum[n - 2*m];
+            self.acc[octave] += (diff/(m as f64)).powi(2);
+            self.count[octave] += 1;
+            octave += 1;
+        }
+    }
+
+    // Returns (number of scans, averaging time, deviation) for each octave.
+    pub fn get(&self, sample_rate: f64) -> Vec<(u32, f64, f64)> {
+        let scan_time = if self.interval_count > 0 {
+            (self.interval_sum as f64)/(self.interval_count as f64)/sample_rate
+        } else {
+            0.0
+        };
+        self.acc.iter().zip(self.count.iter()).enumerate()
+            .map(|(octave, (acc, count))| {
+                let m = 1 << octave;
+                (m, (m as f64)*scan_time, (acc/(2.0*(*count as f64))).sqrt())
+            })
+            .collect()
+    }
+
+    pub fn write(&self, sample_rate: f64, output: &mut impl Write) -> std::io::Result<()> {
+        for (scans, tau, deviation) in self.get(sample_rate) {
+            writeln!(output, "{} {:.4} {:.6}", scans, tau, deviation*1.0e9)?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the Allan deviation files in a background thread, so that slow file systems do
+// not block the processing of the samples. Only the most recent contents of each file are
+// written when the thread falls behind.
+struct AllanFileWriter {
+    sender: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+impl AllanFileWriter {
+    pub fn new() -> AllanFileWriter {
+        let (sender, receiver) = mpsc::channel::<(String, Vec<u8>)>();
+        std::thread::spawn(move || {
+            while let Ok(first) = receiver.recv() {
+                let mut pending = vec![first];
+                for (file_name, contents) in receiver.try_iter() {
+                    pending.retain(|(pending_name, _)| *pending_name != file_name);
+                    pending.push((file_name, contents));
+                }
+                for (file_name, contents) in pending {
+                    if let Err(e) = std::fs::write(&file_name, contents) {
+                        eprintln!("failed to write Allan deviation to {}: {}", file_name, e);
+                    }
+                }
+            }
+        });
+        AllanFileWriter {
+            sender: sender
+        }
+    }
+
+    pub fn write(&self, file_name: String, allan_deviation: &AllanDeviation, sample_rate: f64) {
+        let mut contents = Vec::new();
+        allan_deviation.write(sample_rate, &mut contents).unwrap();
+        self.sender.send((file_name, contents)).unwrap();
+    }
+}
+
+// Relative magnitude of the diagonal of the triangular factor below which the basis
+// functions of a least-squares problem are considered linearly dependent.
+const LEAST_SQUARES_TOLERANCE: f64 = 1.0e-9;
+
+// Solves the linear least-squares problem given by rows of (basis function values, target)
+// through the Householder QR decomposition of the basis function values.
+fn least_squares(n: usize, rows: impl Iterator<Item=(Vec<f64>, f64)>) -> Option<Vec<f64>> {
+    let (mut a, mut b): (Vec<Vec<f64>>, Vec<f64>) = rows.unzip();
+    let m = b.len();
+    if m < n {
+        return None;
+    }
+    for j in 0..n {
+        let norm = (j..m).map(|i| a[i][j]*a[i][j]).sum::<f64>().sqrt();
+        let column_norm = (0..m).map(|i| a[i][j]*a[i][j]).sum::<f64>().sqrt();
+        if !(norm > LEAST_SQUARES_TOLERANCE*column_norm) {
+            return None;
+        }
+        let alpha = if a[j][j] > 0.0 { -norm } else { norm };
+        let mut v: Vec<f64> = (j..m).map(|i| a[i][j]).collect();
+        v[0] -= alpha;
+        let v_norm2: f64 = v.iter().map(|x| x*x).sum();
+        for k in j..n {
+            let factor = 2.0*(j..m).map(|i| v[i - j]*a[i][k]).sum::<f64>()/v_norm2;
+            for i in j..m {
+                a[i][k] -= factor*v[i - j];
+            }
+        }
+        let factor = 2.0*(j..m).map(|i| v[i - j]*b[i]).sum::<f64>()/v_norm2;
+        for i in j..m {
+            b[i] -= factor*v[i - j];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        let sum: f64 = (i+1..n).map(|k| a[i][k]*x[k]).sum();
+        x[i] = (b[i] - sum)/a[i][i];
+    }
+    Some(x)
+}
+
+// Evaluates at time t the least-squares polynomial of the given degree through
+// the (time, position) points.
+fn fit_polynomial<'a>(points: impl Iterator<Item=&'a (u64, i64)> + Clone, t: u64, degree: usize) -> Option<i64> {
+    let origin = points.clone().next()?.1;
+    let coefficients = least_squares(degree + 1, points.map(|&(time, position)| {
+        let x = (time as f64) - (t as f64);
+        ((0..=degree).map(|k| x.powi(k as i32)).collect(), (position - origin) as f64)
+    }))?;
+    Some(origin + coefficients[0].round() as i64)
+}
+
+// Number of MEAS edges per period of the motion retained for fitting the motion model.
+const MODEL_MAX_POINTS: usize = 2000;
+
+// Periodic model of the motion:
+// offset + sum over k of (a_k*cos(2*pi*k*x) + b_k*sin(2*pi*k*x)),
+// with x the time since origin_time in units of period.
+struct MotionModel {
+    origin_time: u64,
+    origin_position: i64,
+    period: f64,
+    coefficients: Vec<f64>, // offset, then a_k and b_k for each harmonic
+    residual: f64,          // RMS fit residual during the scan, in Dpll::TURN units
+}
+
+impl MotionModel {
+    fn basis(harmonics: usize, x: f64) -> Vec<f64> {
+        let mut basis = vec![1.0];
+        for k in 1..=harmonics {
+            let phase = 2.0*std::f64::consts::PI*(k as f64)*x;
+            basis.push(phase.cos());
+            basis.push(phase.sin());
+        }
+        basis
+    }
+
+    fn evaluate(coefficients: &[f64], x: f64) -> f64 {
+        let harmonics = coefficients.len()/2;
+        MotionModel::basis(harmonics, x).iter().zip(coefficients.iter())
+            .map(|(basis, coefficient)| basis*coefficient).sum()
+    }
+
+    // Fits the model with the given period (in samples) to the (time, position) points
+    // during the period of the motion that ends with the scan. The frequency is fixed, and
+    // the points cover a whole period, so that the basis functions are nearly orthogonal.
+    pub fn fit(points: &[(u64, i64)], period: u64, harmonics: usize, scan: (u64, u64)) -> Option<MotionModel> {
+        let n = 2*harmonics + 1;
+        let (scan_start, scan_end) = scan;
+        let fit_start = scan_end.checked_sub(period)?;
+        if points.first()?.0 > fit_start {
+            return None;
+        }
+        let origin_position = points[0].1;
+        let period = period as f64;
+        let samples: Vec<(u64, f64, f64)> = points.iter()
+            .filter(|&&(time, _)| time >= fit_start && time <= scan_end)
+            .map(|&(time, position)| (
+                time,
+                ((time as f64) - (scan_start as f64))/period,
+                (position - origin_position) as f64))
+            .collect();
+        if samples.len() < 2*n {
+            return None;
+        }
+        let coefficients = least_squares(n, samples.iter()
+            .map(|&(_, x, y)| (MotionModel::basis(harmonics, x), y)))?;
+
+        let residuals: Vec<f64> = samples.iter()
+            .filter(|&&(time, _, _)| time >= scan_start)
+            .map(|&(_, x, y)| (y - MotionModel::evaluate(&coefficients, x)).powi(2))
+            .collect();
+        if residuals.is_empty() {
+            return None;
+        }
+        Some(MotionModel {
+            origin_time: scan_start,
+            origin_position: origin_position,
+            period: period,
+            coefficients: coefficients,
+            residual: (residuals.iter().sum::<f64>()/(residuals.len() as f64)).sqrt(),
+        })
+    }
+
+    pub fn position(&self, time: u64) -> i64 {
+        let x = ((time as f64) - (self.origin_time as f64))/self.period;
+        self.origin_position + MotionModel::evaluate(&self.coefficients, x).round() as i64
+    }
+}
+
+// Outcome of fitting the motion model to a scan.
+#[derive(Debug, Clone, Copy)]
+enum ModelFit {
+    Accepted(f64),          // RMS fit residual, in m.
+    Rejected,
+}
+
+// Fit of the motion model to a scan, sent to the fitting thread. The generation
+// identifies the requests made since the last reset of the fringe tagger.
+struct ModelFitRequest {
+    generation: u64,
+    points: Vec<(u64, i64)>,
+    period: Option<u64>,
+    harmonics: usize,
+    scan: (u64, u64),
+}
+
+// Fits the motion models in a background thread, so that the processing of the samples
+// does not stall at the end of each scan.
+struct ModelFitter {
+    sender: mpsc::Sender<ModelFitRequest>,
+    receiver: mpsc::Receiver<(u64, Option<MotionModel>)>,
+}
+
+impl ModelFitter {
+    pub fn new() -> ModelFitter {
+        let (sender, requests) = mpsc::channel::<ModelFitRequest>();
+        let (results, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for request in requests.iter() {
+                let model = request.period.and_then(|period|
+                    MotionModel::fit(&request.points, period, request.harmonics, request.scan));
+                if results.send((request.generation, model)).is_err() {
+                    break;
+                }
+            }
+        });
+        ModelFitter {
+            sender: sender,
+            receiver: receiver,
+        }
+    }
+}
+
+// Number of samples between polls of the commands from standard input.
+const COMMAND_POLL_INTERVAL: u64 = 1 << 16;
+
+// Determines the position of each input laser fringe according to config.fringe_position.
+// As the positions of some fringes are only known later, the Start and End events of the
+// scans are passed through the tagger, which emits all events in the order of their times.
+struct FringeTagger {
+    mode: FringePosition,
+    delay: u64,
+    turn_length: f64,
+    pending: VecDeque<u64>,
+    markers: VecDeque<(u64, FringeCounterEvent)>,
+    edges: VecDeque<(u64, i64)>,
+    // State of the motion model: the number of pending fringes of each scan being fitted
+    // and of the current scan, and the times of the current and last scan starts.
+    fitter: Option<ModelFitter>,
+    generation: u64,
+    fits: VecDeque<usize>,
+    scan_fringes: Option<usize>,
+    scan_start: u64,
+    last_start: Option<u64>,
+    period: Option<u64>,
+}
+
+impl FringeTagger {
+    pub fn new(config: &Config, mode: FringePosition, delay: u64) -> FringeTagger {
+        let fitter = match mode {
+            FringePosition::Model { .. } => Some(ModelFitter::new()),
+            _ => None
+        };
+        FringeTagger {
+            mode: mode,
+            delay: delay,
+            turn_length: config.ref_wavelength/(noptica::Dpll::TURN as f64),
+            pending: VecDeque::new(),
+            markers: VecDeque::new(),
+            edges: VecDeque::new(),
+            fitter: fitter,
+            generation: 0,
+            fits: VecDeque::new(),
+            scan_fringes: None,
+            scan_start: 0,
+            last_start: None,
+            period: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.markers.clear();
+        self.edges.clear();
+        self.generation += 1;
+        self.fits.clear();
+        self.scan_fringes = None;
+        self.last_start = None;
+        self.period = None;
+    }
+
+    // Time of the earliest fringe whose position is not known yet.
+    pub fn unresolved(&self) -> Option<u64> {
+        self.pending.front().cloned()
+    }
+
+    // Emits the held Start and End events that precede a fringe at the given time, or all
+    // of them. At the same time, Start precedes the fringe and End follows it.
+    fn emit_markers(&mut self, before: Option<u64>, emit: &mut impl FnMut(u64, FringeCounterEvent)) {
+        while let Some(&(time, event)) = self.markers.front() {
+            if let Some(before) = before {
+                if time > before || (time == before && event == FringeCounterEvent::End) {
+                    break;
+                }
+            }
+            self.markers.pop_front();
+            emit(time, event);
+        }
+    }
+
+    fn emit_fringe(&mut self, time: u64, position: i64, emit: &mut impl FnMut(u64, FringeCounterEvent)) {
+        self.emit_markers(Some(time), emit);
+        emit(time, FringeCounterEvent::Fringe(position));
+    }
+
+    // Discards the fringes of the current scan of the motion model.
+    fn drop_scan(&mut self) {
+        if let Some(scan_fringes) = self.scan_fringes.take() {
+            let len = self.pending.len();
+            self.pending.truncate(len - scan_fringes);
+        }
+    }
+
+    // Called at the start of each scan.
+    pub fn start(&mut self, time: u64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        if self.fitter.is_some() {
+            self.period = self.last_start.map(|last_start| time - last_start);
+            self.last_start = Some(time);
+            self.drop_scan();
+            self.scan_fringes = Some(0);
+            self.scan_start = time;
+        }
+        self.markers.push_back((time, FringeCounterEvent::Start));
+        self.emit_markers(self.unresolved(), &mut emit);
+    }
+
+    // Called at the end of each scan. In model mode, submits the scan for fitting.
+    pub fn end(&mut self, time: u64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        self.markers.push_back((time, FringeCounterEvent::End));
+        if let (Some(fitter), Some(scan_fringes)) = (&self.fitter, self.scan_fringes.take()) {
+            let harmonics = match self.mode {
+                FringePosition::Model { harmonics, .. } => harmonics,
+                _ => unreachable!()
+            };
+            fitter.sender.send(ModelFitRequest {
+                generation: self.generation,
+                points: self.edges.iter().cloned().collect(),
+                period: self.period,
+                harmonics: harmonics,
+                scan: (self.scan_start, time),
+            }).unwrap();
+            self.fits.push_back(scan_fringes);
+        }
+        self.emit_markers(self.unresolved(), &mut emit);
+    }
+
+    // Called at each sample in model mode. Emits the positions of the fringes of the next
+    // scan whose fit has completed if the fit is acceptable, and returns the outcome of the fit.
+    pub fn poll(&mut self, mut emit: impl FnMut(u64, FringeCounterEvent)) -> Option<ModelFit> {
+        let max_residual = match self.mode {
+            FringePosition::Model { max_residual, .. } => max_residual,
+            _ => return None
+        };
+        let model = loop {
+            match self.fitter.as_ref().unwrap().receiver.try_recv() {
+                Ok((generation, model)) if generation == self.generation => break model,
+                Ok(_) => (),
+                Err(_) => return None
+            }
+        };
+        let scan_fringes = self.fits.pop_front().unwrap();
+        let residual = model.as_ref().map(|model| model.residual*self.turn_length);
+        let fit = match (&model, residual) {
+            (Some(model), Some(residual)) if residual*1.0e9 <= max_residual => {
+                for _ in 0..scan_fringes {
+                    let time = self.pending.pop_front().unwrap();
+                    self.emit_fringe(time, model.position(time), &mut emit);
+                }
+                ModelFit::Accepted(residual)
+            },
+            _ => {
+                if let Some(residual) = residual {
+                    eprintln!("scan rejected (motion model residual {:.3} nm)", residual*1.0e9);
+                }
+                self.pending.drain(..scan_fringes);
+                ModelFit::Rejected
+            }
+        };
+        self.emit_markers(self.unresolved(), &mut emit);
+        Some(fit)
+    }
+
+    // Called for each fringe.
+    pub fn fringe(&mut self, time: u64, position: i64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        match self.mode {
+            FringePosition::Hold => self.emit_fringe(time, position, &mut emit),
+            FringePosition::Delayed | FringePosition::Linear | FringePosition::Polynomial(_) =>
+                self.pending.push_back(time),
+            FringePosition::Model { .. } => {
+                if let Some(scan_fringes) = &mut self.scan_fringes {
+                    *scan_fringes += 1;
+                    self.pending.push_back(time);
+                }
+            }
+        }
+    }
+
+    // Called at each MEAS edge, emits the positions of the fringes for which enough
+    // subsequent edges have been received for interpolation.
+    pub fn meas_edge(&mut self, time: u64, position: i64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        let (edges_per_side, degree) = match self.mode {
+            FringePosition::Linear => (1, 1),
+            FringePosition::Polynomial(n) => (n, 2),
+            FringePosition::Model { .. } => {
+                // Retains MODEL_MAX_POINTS edges per period over the last two periods.
+                if let Some(period) = self.period {
+                    let interval = period/(MODEL_MAX_POINTS as u64);
+                    if self.edges.back().is_none_or(|&(last, _)| time >= last + interval) {
+                        self.edges.push_back((time, position));
+                    }
+                    while self.edges.front().is_some_and(|&(first, _)| first + 2*period < time) {
+                        self.edges.pop_front();
+                    }
+                }
+                return;
+            },
+            _ => return
+        };
+        self.edges.push_back((time, position));
+        if self.edges.len() > 2*edges_per_side + 2 {
+            self.edges.pop_front();
+        }
+        while let Some(&fringe_time) = self.pending.front() {
+            let after = self.edges.iter().filter(|&&(edge_time, _)| edge_time > fringe_time).count();
+            if after < edges_per_side {
+                break;
+            }
+            self.pending.pop_front();
+            let before = self.edges.len() - after;
+            if before < edges_per_side {
+                eprintln!("fringe dropped (insufficient MEAS edge history)");
+                continue;
+            }
+            let points = self.edges.range(before - edges_per_side..before + edges_per_side);
+            match fit_polynomial(points, fringe_time, degree) {
+                Some(fringe) => self.emit_fringe(fringe_time, fringe, &mut emit),
+                None => eprintln!("fringe dropped (interpolation failed)")
+            }
+        }
+        self.emit_markers(self.unresolved(), &mut emit);
+    }
+
+    // Called at each sample with the filtered position, emits the positions of the delayed
+    // fringes that occurred one group delay earlier.
+    pub fn tick(&mut self, time: u64, f_position: i64, mut emit: impl FnMut(u64, FringeCounterEvent)) {
+        if self.mode != FringePosition::Delayed {
+            return;
+        }
+        while let Some(&fringe_time) = self.pending.front() {
+            if fringe_time + self.delay > time {
+                break;
+            }
+            self.pending.pop_front();
+            self.emit_fringe(fringe_time, f_position, &mut emit);
+        }
+        self.emit_markers(self.unresolved(), &mut emit);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FringeCounterOutput {
+    Wavelength(f64),        // Wavelength measured during a scan, in m.
+    Invalid,                // The scan failed the fringe checks.
+    Restart,                // Counting restarts with the acquisition of new limits.
+}
+
+// Combines the wavelengths of consecutive scans, either in blocks of config.decimation
+// scans or over a sliding window.
+struct ScanAverager {
+    scans: VecDeque<(u64, f64)>,
+    count: u32,
+}
+
+impl ScanAverager {
+    pub fn new() -> ScanAverager {
+        ScanAverager {
+            scans: VecDeque::new(),
+            count: 0,
+        }
+    }
+
+    // Returns the scans to average and the number of failed scans when an output is due.
+    pub fn input(&mut self, config: &Config, time: u64, output: FringeCounterOutput)
+            -> Option<(Vec<(u64, f64)>, usize)> {
+        match output {
+            FringeCounterOutput::Wavelength(wavelength) => self.scans.push_back((time, wavelength)),
+            FringeCounterOutput::Invalid => (),
+            FringeCounterOutput::Restart => {
+                // A new block starts with the new limits.
+                if config.sliding_window.is_none() {
+                    self.scans.clear();
+                    self.count = 0;
+                }
+                return None;
+            }
+        }
+        self.count += 1;
+        match &config.sliding_window {
+            None => {
+                if self.count == config.decimation {
+                    let scans: Vec<(u64, f64)> = self.scans.drain(..).collect();
+                    let failed = (self.count as usize) - scans.len();
+                    self.count = 0;
+                    Some((scans, failed))
+                } else {
+                    None
+                }
+            },
+            Some(sliding_window) => {
+                if let FringeCounterOutput::Invalid = output {
+                    return None;
+                }
+                let window_start = match *sliding_window {
+                    SlidingWindow::Scans(n) => self.scans.len().saturating_sub(n),
+                    SlidingWindow::Time(t) => {
+                        let duration = (t*config.sample_rate) as u64;
+                        self.scans.iter().position(|&(scan_time, _)| scan_time + duration >= time).unwrap()
+                    }
+                };
+                self.scans.drain(..window_start);
+                Some((self.scans.iter().cloned().collect(), 0))
+            }
+        }
+    }
+}
+
+// Decimates the wavelengths of the accepted scans with config.decimator. The wavelengths
+// are decimated as integers, in attometers.
+struct ScanDecimator {
+    decimator: Box<dyn noptica::Decimate>,
+    failed: usize,
+}
+
+impl ScanDecimator {
+    pub fn new(config: &Config) -> Option<ScanDecimator> {
+        config.decimator.as_ref().map(|decimator| ScanDecimator {
+            decimator: decimator.create(config.decimation).unwrap(),
+            failed: 0,
+        })
+    }
+
+    // Returns the decimated wavelengths and the number of failed scans when an output is due.
+    pub fn input(&mut self, output: FringeCounterOutput) -> Option<(Vec<f64>, usize)> {
+        match output {
+            FringeCounterOutput::Wavelength(wavelength) => {
+                let values = self.decimator.input((wavelength*1.0e18).round() as i64)?;
+                let failed = std::mem::replace(&mut self.failed, 0);
+                Some((values.iter().map(|&value| (value as f64)*1.0e-18).collect(), failed))
+            },
+            FringeCounterOutput::Invalid => {
+                self.failed += 1;
+                None
+            },
+            FringeCounterOutput::Restart => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CalibrationPoint {
+    known: f64,             // Known wavelength of the input laser in m.
+    measured: f64,          // Wavelength measured by the uncalibrated wavemeter in m.
+    uncertainty: f64,       // Standard uncertainty of the measured wavelength in m.
+}
+
+// Linear correction of the measured wavelengths, fitted to one or more known lines.
+// With a single line, it reduces to a scale factor on the reference wavelength.
+#[derive(Serialize, Deserialize, Debug)]
+struct Calibration {
+    points: Vec<CalibrationPoint>,
+    offset: f64,            // in m
+    scale: f64,
+    offset_uncertainty: f64,
+    scale_uncertainty: f64,
+}
+
+impl Calibration {
+    pub fn new() -> Calibration {
+        Calibration {
+            points: Vec::new(),
+            offset: 0.0,
+            scale: 1.0,
+            offset_uncertainty: 0.0,
+            scale_uncertainty: 0.0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Calibration, Box<dyn Error>> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    pub fn apply(&self, wavelength: f64) -> f64 {
+        self.offset + self.scale*wavelength
+    }
+
+    // Adds or replaces a point and fits the correction (weighted least squares).
+    pub fn add_point(&mut self, point: CalibrationPoint) {
+        self.points.retain(|p| (p.known - point.known).abs() > 1.0e-15);
+        self.points.push(point);
+
+        if self.points.len() == 1 {
+            let point = &self.points[0];
+            self.offset = 0.0;
+            self.scale = point.known/point.measured;
+            self.offset_uncertainty = 0.0;
+            self.scale_uncertainty = self.scale*point.uncertainty/point.measured;
+        } else {
+            let weights: Vec<f64> = self.points.iter().map(|p| 1.0/p.uncertainty.powi(2)).collect();
+            let sw: f64 = weights.iter().sum();
+            let mx = self.points.iter().zip(weights.iter()).map(|(p, w)| w*p.measured).sum::<f64>()/sw;
+            let my = self.points.iter().zip(weights.iter()).map(|(p, w)| w*p.known).sum::<f64>()/sw;
+            let sxx: f64 = self.points.iter().zip(weights.iter())
+                .map(|(p, w)| w*(p.measured - mx).powi(2)).sum();
+            let sxy: f64 = self.points.iter().zip(weights.iter())
+                .map(|(p, w)| w*(p.measured - mx)*(p.known - my)).sum();
+            self.scale = sxy/sxx;
+            self.offset = my - self.scale*mx;
+            self.scale_uncertainty = (1.0/sxx).sqrt();
+            self.offset_uncertainty = (1.0/sw + mx*mx/sxx).sqrt();
+        }
+    }
+}
+
+fn correct_wavelength(config: &Config, air_monitor: &Option<noptica::AirMonitor>,
+        calibration: &Option<Calibration>, wavelength: f64) -> f64 {
+    let wavelength = match air_monitor {
+        Some(air_monitor) => air_monitor.get().dispersion_correction(config.ref_wavelength, wavelength),
+        None => wavelength
+    };
+    match calibration {
+        Some(calibration) => calibration.apply(wavelength),
+        None => wavelength
+    }
+}
+
+// Reference calibration: wavelength averages of one input are collected
+// while a laser of known wavelength is measured.
+struct CalibrationRun {
+    channel: usize,
+    known: f64,             // in m
+    outputs: usize,
+    means: Vec<f64>,
+    sem: f64,
+    calibration: Calibration,   // Existing calibration to which the point is added
+}
+
+struct Average {
+    mean: f64,
+    std: f64,
+    sem: f64,
+    rejected: usize,        // Number of failed and rejected scans.
+}
+
+// Averages the scan wavelengths after outlier rejection. Returns None if no scans remain.
+fn average(config: &Config, scans: &[(u64, f64)], failed: usize) -> Option<Average> {
+    let mut scans = scans.to_vec();
+    let count = scans.len();
+    if let Some(rejection) = &config.rejection {
+        reject_outliers(&mut scans, rejection);
+    }
+    if scans.is_empty() {
+        return None;
+    }
+    let wavelengths: Vec<f64> = scans.iter().map(|&(_, v)| v).collect();
+    let (mean, std) = mean_std(&wavelengths);
+    Some(Average {
+        mean: mean,
+        std: std,
+        sem: std/(wavelengths.len() as f64).sqrt(),
+        rejected: failed + count - scans.len(),
+    })
+}
+
 macro_rules! generator_input {
     ($e:expr) => ({ yield (); $e.get() })
 }
 
-fn do_wavemeter(config: &Config) {
-    let mut refpll = noptica::Dpll::new(
-        noptica::Dpll::frequency_to_ftw(config.ref_min, config.sample_rate),
-        noptica::Dpll::frequency_to_ftw(config.ref_max, config.sample_rate),
-        config.refpll_ki,
-        config.refpll_kp);
-    let mut position_tracker = noptica::PositionTracker::new();
-    let mut position = 0;
-    let motion_filter_coeffs = biquad::Coefficients::<f64>::from_params(
-        biquad::Type::LowPass,
-        biquad::frequency::Hertz::<f64>::from_hz(config.sample_rate).unwrap(),
-        biquad::frequency::Hertz::<f64>::from_hz(config.motion_cutoff).unwrap(),
-        biquad::Q_BUTTERWORTH_F64).unwrap();
-    let mut motion_filter = biquad::DirectForm2Transposed::<f64>::new(motion_filter_coeffs);
-    let mut min_max_monitor = MinMaxMonitor::new((config.sample_rate*config.position_mon_time) as u32);
-    let mut quadrant_tracker = QuadrantTracker::new();
-
-    let fringe_counter_input = Cell::new(FringeCounterEvent::Start);
-    let mut fringe_counter = || {
+// Counts the input laser fringes during each scan, using the Start/Fringe/End events
+// received through the input cell, and reports the result of each scan through the output cell.
+fn fringe_counter<'a>(config: &'a Config, input: &'a Cell<FringeCounterEvent>,
+        output: &'a Cell<Option<FringeCounterOutput>>) -> impl Generator<Yield=(), Return=()> + Unpin + 'a {
+    move || {
+        // With turnaround_hysteresis, the window of each scan is derived from its own stroke,
+        // so the limits are acquired again for each scan and failed scans are reported
+        // individually.
+        let per_stroke = config.turnaround_hysteresis.is_some();
+        let failed = if per_stroke { FringeCounterOutput::Invalid } else { FringeCounterOutput::Restart };
+        // Set when an unexpected Start event has been received, which begins the next scan.
+        let mut started = false;
         'outer: loop {
-            loop {
-                if let FringeCounterEvent::Start = generator_input!(fringe_counter_input) {
-                    break;
+            if !started {
+                loop {
+                    if let FringeCounterEvent::Start = generator_input!(input) {
+                        break;
+                    }
                 }
             }
+            started = false;
 
             let mut boundary_fringes = [0i64; 4];
             for i in 0..4 {
-                if let FringeCounterEvent::Fringe(position) = generator_input!(fringe_counter_input) {
-                    boundary_fringes[i] = position;
-                } else {
-                    eprintln!("unexpected event (boundary fringe acquisition)");
-                    continue 'outer;
+                match generator_input!(input) {
+                    FringeCounterEvent::Fringe(position) => boundary_fringes[i] = position,
+                    event => {
+                        eprintln!("unexpected event (boundary fringe acquisition)");
+                        started = event == FringeCounterEvent::Start;
+                        output.set(Some(FringeCounterOutput::Restart));
+                        continue 'outer;
+                    }
                 }
             }
 
+            let nominal_distance = boundary_fringes[1] - boundary_fringes[0];
+            let jitter_tol = ((nominal_distance as f64)*config.fringe_jitter_tol) as i64;
+            let above_tolerance = move |distance: i64| (distance - nominal_distance).abs() > jitter_tol;
+            let mut jitter = [boundary_fringes[2] - boundary_fringes[1], boundary_fringes[3] - boundary_fringes[2]]
+                .iter().cloned().find(|&distance| above_tolerance(distance));
+
             let mut fringes_between_boundary = 0;
             loop {
-                match generator_input!(fringe_counter_input) {
+                match generator_input!(input) {
                     FringeCounterEvent::Start => {
                         eprintln!("unexpected event (initial fringe counting)");
+                        started = true;
+                        output.set(Some(FringeCounterOutput::Restart));
                         continue 'outer;
                     },
                     FringeCounterEvent::Fringe(position) => {
+                        let distance = position - boundary_fringes[3];
+                        if jitter.is_none() && above_tolerance(distance) {
+                            jitter = Some(distance);
+                        }
                         boundary_fringes[2] = boundary_fringes[3];
                         boundary_fringes[3] = position;
                         fringes_between_boundary += 1;
@@ -292,123 +1662,781 @@ fn do_wavemeter(config: &Config) {
 
             if fringes_between_boundary < config.min_fringes {
                 eprintln!("insufficient fringes between boundary ({})", fringes_between_boundary);
+                output.set(Some(failed));
+                continue 'outer;
+            }
+            if let Some(distance) = jitter {
+                eprintln!("distance between fringes above tolerance (got {}, nominal {})",
+                    distance, nominal_distance);
+                output.set(Some(failed));
                 continue 'outer;
             }
-
-            let nominal_distance = boundary_fringes[1] - boundary_fringes[0];
-            let jitter_tol = ((nominal_distance as f64)*config.fringe_jitter_tol) as i64;
 
             let limit1 = (boundary_fringes[0] + boundary_fringes[1])/2;
             let limit2 = (boundary_fringes[2] + boundary_fringes[3])/2;
             let expected_fringes = fringes_between_boundary + 2;
-            let mut f1_acc = boundary_fringes[1];
-            let mut f2_acc = boundary_fringes[2];
+            let scan_wavelength = move |f1: i64, f2: i64| {
+                ((f2 - f1).abs() as f64)/((expected_fringes - 1) as f64)
+                    *config.ref_wavelength/(noptica::Dpll::TURN as f64)
+            };
+            output.set(Some(FringeCounterOutput::Wavelength(
+                scan_wavelength(boundary_fringes[1], boundary_fringes[2]))));
+            if per_stroke {
+                continue 'outer;
+            }
 
-            for _ in 0..config.decimation-1 {
-                loop {
-                    if let FringeCounterEvent::Start = generator_input!(fringe_counter_input) {
-                        break;
+            // In block mode, new limits are acquired for each block. Bad scans are reported
+            // individually so that the block keeps its length and the output rate stays steady.
+            let mut scan_count = 1;
+            while config.sliding_window.is_some() || scan_count < config.decimation {
+                if !started {
+                    loop {
+                        if let FringeCounterEvent::Start = generator_input!(input) {
+                            break;
+                        }
                     }
                 }
+                started = false;
+                let mut first_fringe = 0;
                 let mut last_fringe: Option<i64> = None;
                 let mut count: u32 = 0;
+                let mut valid = true;
                 loop {
-                    match generator_input!(fringe_counter_input) {
+                    match generator_input!(input) {
                         FringeCounterEvent::Start => {
                             eprintln!("unexpected event (secondary fringe counting)");
-                            continue 'outer;
+                            valid = false;
+                            started = true;
+                            break;
                         },
                         FringeCounterEvent::Fringe(position) => {
                             if (position > limit1) && (position < limit2)
                                     || (position > limit2) && (position < limit1) {
                                 if let Some(last_fringe) = last_fringe {
                                     let distance = position - last_fringe;
-                                    if (distance - nominal_distance).abs() > jitter_tol {
+                                    if valid && (distance - nominal_distance).abs() > jitter_tol {
                                         eprintln!("distance between fringes above tolerance (got {}, nominal {})",
                                             distance, nominal_distance);
-                                        continue 'outer;
+                                        valid = false;
                                     }
                                 }
                                 last_fringe = Some(position);
                                 count += 1;
                                 if count == 1 {
-                                    f1_acc += position;
+                                    first_fringe = position;
                                 }
                             }
                         },
                         FringeCounterEvent::End => break,
                     }
                 }
-                if count == expected_fringes {
-                    f2_acc += last_fringe.unwrap();
-                } else {
+                if valid && count != expected_fringes {
                     eprintln!("unexpected fringe count (got {}, expected {})", count, expected_fringes);
+                    valid = false;
+                }
+                scan_count += 1;
+
+                if valid {
+                    output.set(Some(FringeCounterOutput::Wavelength(
+                        scan_wavelength(first_fringe, last_fringe.unwrap()))));
+                } else if config.sliding_window.is_some() {
+                    // The limits may no longer match the motion; acquire new ones.
+                    output.set(Some(FringeCounterOutput::Restart));
                     continue 'outer;
+                } else {
+                    output.set(Some(FringeCounterOutput::Invalid));
                 }
             }
-
-            let f1_avg = f1_acc/(config.decimation as i64);
-            let f2_avg = f2_acc/(config.decimation as i64);
-            let wavelength = (f2_avg - f1_avg).abs()/(expected_fringes as i64 - 1);
-            let wavelength_nm = (wavelength as f64)*config.ref_wavelength/(noptica::Dpll::TURN as f64);
-            println!("{:.4}", wavelength_nm*1.0e9);
         }
+    }
+}
+
+fn create_motion_filter(config: &Config) -> noptica::FilterCascade {
+    let default_design = noptica::FilterDesign::Butterworth { order: 2, cutoff: config.motion_cutoff };
+    noptica::FilterCascade::new(config.motion_filter.as_ref().unwrap_or(&default_design), config.sample_rate)
+        .unwrap_or_else(|e| { eprintln!("invalid motion filter: {}", e); std::process::exit(1) })
+}
+
+// MEAS edges arrive at the REF frequency shifted by the Doppler effect, therefore
+// the attenuation is also given at the limits of the REF frequency range.
+fn print_motion_filter_info(config: &Config, motion_filter: &noptica::FilterCascade) {
+    let ref_nominal = (config.ref_min + config.ref_max)/2.0;
+    let group_delay = motion_filter.group_delay(0.0, config.sample_rate);
+    eprintln!("motion filter: group delay {:.1} samples ({:.3} us)",
+        group_delay, 1.0e6*group_delay/config.sample_rate);
+    let mut frequencies = vec![("REF", ref_nominal), ("REF min", config.ref_min), ("REF max", config.ref_max)];
+    // The Doppler shift of MEAS is one cycle per wavelength of optical path.
+    if let Some(max_velocity) = config.max_velocity {
+        let doppler = max_velocity/config.ref_wavelength;
+        frequencies.push(("MEAS min", (config.ref_min - doppler).max(0.0)));
+        frequencies.push(("MEAS max", config.ref_max + doppler));
+    }
+    for &(label, frequency) in frequencies.iter() {
+        eprintln!("motion filter: attenuation at {} ({:.3} MHz): {:.1} dB",
+            label, frequency*1.0e-6, motion_filter.attenuation(frequency, config.sample_rate));
+    }
+}
 
+fn do_wavemeter(config: &Config, mut calibration_run: Option<CalibrationRun>) {
+    let mut refpll = noptica::Dpll::new(
+        noptica::Dpll::frequency_to_ftw(config.ref_min, config.sample_rate),
+        noptica::Dpll::frequency_to_ftw(config.ref_max, config.sample_rate),
+        config.refpll_ki,
+        config.refpll_kp);
+    let mut position_tracker = noptica::PositionTracker::new();
+    let mut dmi_origin = DmiOrigin::new();
+    let mut position = 0;
+    let mut motion_filter = create_motion_filter(config);
+    if config.debug {
+        print_motion_filter_info(config, &motion_filter);
+    }
+    let mut min_max_monitor = MinMaxMonitor::new((config.sample_rate*config.position_mon_time) as u32);
+    let mut quadrant_tracker = match &config.quadrant {
+        Some(quadrant) => QuadrantTracker::with_tuning(
+            (quadrant.hysteresis*1.0e-9/config.ref_wavelength*(noptica::Dpll::TURN as f64)) as i64,
+            (quadrant.min_dwell*config.sample_rate) as u32,
+            quadrant.resync_after),
+        None => QuadrantTracker::new()
     };
-    let mut fringe_counter_event = |event: FringeCounterEvent| {
-        fringe_counter_input.set(event);
-        Pin::new(&mut fringe_counter).resume();
+    let air_monitor = config.air.as_ref().map(noptica::AirMonitor::new);
+    let calibration = match &config.calibration_file {
+        Some(calibration_file) if calibration_run.is_none() && Path::new(calibration_file).exists() =>
+            Some(load_calibration(calibration_file)),
+        _ => None
     };
+    let commands = noptica::stdin_commands();
+    let mut sample_clock = 0u64;
+    let mut events: Vec<(u64, FringeCounterEvent)> = Vec::new();
+    let mut f_position = 0;
+    let mut window = config.window;
+    if window.is_some() && config.turnaround_hysteresis.is_some() {
+        eprintln!("window and turnaround_hysteresis cannot be used together");
+        std::process::exit(1);
+    }
+
+    // Each channel has its own fringe counter, averager and output stream, and shares
+    // the position and quadrant tracking. The first channels correspond to the inputs,
+    // followed by the comparison channels if config.fringe_position_compare is set.
+    let fringe_position = config.fringe_position.unwrap_or(FringePosition::Hold);
+    let mut channels: Vec<(usize, String, FringePosition)> = config.inputs.iter().enumerate()
+        .map(|(index, input)| (index, input.name.clone(), fringe_position)).collect();
+    if let Some(compare) = config.fringe_position_compare {
+        for (index, input) in config.inputs.iter().enumerate() {
+            let name = if input.name.is_empty() {
+                compare.name()
+            } else {
+                format!("{}/{}", input.name, compare.name())
+            };
+            channels.push((index, name, compare));
+        }
+    }
+    let fringe_counter_inputs: Vec<Cell<FringeCounterEvent>> = channels.iter()
+        .map(|_| Cell::new(FringeCounterEvent::Start)).collect();
+    let fringe_counter_outputs: Vec<Cell<Option<FringeCounterOutput>>> = channels.iter()
+        .map(|_| Cell::new(None)).collect();
+    let mut fringe_counters: Vec<_> = fringe_counter_inputs.iter().zip(fringe_counter_outputs.iter())
+        .map(|(input, output)| fringe_counter(config, input, output)).collect();
+    let mut scan_averagers: Vec<ScanAverager> = channels.iter()
+        .map(|_| ScanAverager::new()).collect();
+    let mut scan_decimators: Vec<Option<ScanDecimator>> = channels.iter()
+        .map(|_| ScanDecimator::new(config)).collect();
+    let allan_deviations: Vec<RefCell<AllanDeviation>> = channels.iter()
+        .map(|_| RefCell::new(AllanDeviation::new())).collect();
+    let allan_file_writer = config.allan_file.as_ref().map(|_| AllanFileWriter::new());
+    let fringe_delay = motion_filter.group_delay(0.0, config.sample_rate).round() as u64;
+    let mut fringe_taggers: Vec<FringeTagger> = channels.iter()
+        .map(|&(_, _, mode)| FringeTagger::new(config, mode, fringe_delay)).collect();
+    // Sum of the RMS residuals, number of accepted and number of rejected scans of the
+    // motion model fits since the last output of each channel.
+    let model_fits: Vec<Cell<(f64, u32, u32)>> = channels.iter()
+        .map(|_| Cell::new((0.0, 0, 0))).collect();
+    let mut turnaround_tracker = config.turnaround_hysteresis.map(|hysteresis|
+        TurnaroundTracker::new((hysteresis*1.0e-9/config.ref_wavelength*(noptica::Dpll::TURN as f64)) as i64));
+    let mut stroke_buffers: Option<Vec<StrokeBuffer>> = config.turnaround_hysteresis
+        .map(|_| channels.iter().map(|_| StrokeBuffer::new()).collect());
+    if turnaround_tracker.is_some() && channels.iter().any(|&(_, _, mode)| match mode {
+            FringePosition::Model { .. } => true,
+            _ => false
+        }) {
+        eprintln!("the motion model cannot be used with turnaround_hysteresis");
+        std::process::exit(1);
+    }
+    // Only the primary channels drive the locks.
+    let mut locks: Vec<Option<lock::Lock>> = config.inputs.iter()
+        .map(|input| input.lock.as_ref().map(|lock_config| lock::Lock::new(lock_config)
+            .unwrap_or_else(|e| { eprintln!("failed to start lock of {}: {}", input.name, e); std::process::exit(1) })))
+        .collect();
 
-    noptica::sample(&config.sample_command, |rising, _falling| {
+    let mut fringe_counter_event = |channel: usize, time: u64, event: FringeCounterEvent| {
+        let name = &channels[channel].1;
+        let prefix = if name.is_empty() { String::new() } else { format!("{} ", name) };
+        fringe_counter_inputs[channel].set(event);
+        Pin::new(&mut fringe_counters[channel]).resume();
+        let output = match fringe_counter_outputs[channel].take() {
+            Some(FringeCounterOutput::Wavelength(wavelength)) => {
+                let wavelength = correct_wavelength(config, &air_monitor, &calibration, wavelength);
+                allan_deviations[channel].borrow_mut().input(time, wavelength);
+                FringeCounterOutput::Wavelength(wavelength)
+            },
+            Some(output) => {
+                allan_deviations[channel].borrow_mut().gap();
+                output
+            },
+            None => return
+        };
+        let average = match &mut scan_decimators[channel] {
+            Some(scan_decimator) => scan_decimator.input(output).map(|(values, failed)| {
+                let columns: Vec<String> = values.iter().map(|value| format!("{:.4}", value*1.0e9)).collect();
+                (values[0], std::f64::NAN, format!("{}{} {}", prefix, columns.join(" "), failed))
+            }),
+            None => scan_averagers[channel].input(config, time, output).and_then(|(scans, failed)| {
+                match average(config, &scans, failed) {
+                    Some(average) => Some((average.mean, average.sem, format!("{}{:.4} {:.4} {:.4} {}", prefix,
+                        average.mean*1.0e9, average.std*1.0e9, average.sem*1.0e9, average.rejected))),
+                    None => {
+                        eprintln!("{}no valid scans ({} failed)", prefix, failed);
+                        None
+                    }
+                }
+            })
+        };
+        if let Some((mean, sem, mut line)) = average {
+            if let Some(Some(lock)) = locks.get_mut(channel) {
+                let status = lock.update((time as f64)/config.sample_rate, mean*1.0e9);
+                line += &format!(" {:.4} {} {:?}", status.error, status.output, status.state);
+            }
+            if let FringePosition::Model { .. } = channels[channel].2 {
+                let (residual_sum, accepted, rejected) = model_fits[channel].take();
+                line += &format!(" fit {:.4} {}", residual_sum*1.0e9/(accepted.max(1) as f64), rejected);
+            }
+            println!("{}", line);
+            if let Some(run) = &mut calibration_run {
+                if run.channel == channel {
+                    run.means.push(mean);
+                    run.sem = sem;
+                    if run.means.len() == run.outputs {
+                        finish_calibration(config, run);
+                    }
+                }
+            }
+            if let (Some(allan_file), Some(allan_file_writer)) = (&config.allan_file, &allan_file_writer) {
+                allan_file_writer.write(allan_file.replace("{name}", &name.replace("/", "-")),
+                    &allan_deviations[channel].borrow(), config.sample_rate);
+            }
+        }
+    };
+
+    noptica::sample(&config.sample_command, config.sample_bits.unwrap_or(4), |rising, _falling| {
+        sample_clock += 1;
+        if sample_clock % COMMAND_POLL_INTERVAL == 0 {
+            for command in commands.try_iter() {
+                let words: Vec<&str> = command.split_whitespace().collect();
+                let to_um = |position: i64| (position as f64)/(noptica::Dpll::TURN as f64)*config.ref_wavelength*1.0e6;
+                match words.as_slice() {
+                    ["allan"] => {
+                        for ((_, name, _), allan_deviation) in channels.iter().zip(allan_deviations.iter()) {
+                            if !name.is_empty() {
+                                eprintln!("{}:", name);
+                            }
+                            allan_deviation.borrow().write(config.sample_rate, &mut std::io::stderr()).unwrap();
+                        }
+                    },
+                    ["window", start, end] if config.turnaround_hysteresis.is_none() => {
+                        match (start.parse(), end.parse()) {
+                            (Ok(start), Ok(end)) => {
+                                let home = window.and_then(|window| window.home);
+                                window = Some(WindowConfig { start: start, end: end, home: home });
+                            },
+                            _ => eprintln!("invalid window: {}", command)
+                        }
+                    },
+                    ["home"] | ["home", _] => {
+                        let home = match words.get(1) {
+                            Some(home) => home.parse().ok(),
+                            None => Some(to_um(f_position))
+                        };
+                        match (&mut window, home) {
+                            (Some(window), Some(home)) => {
+                                window.home = Some(home);
+                                eprintln!("home {:.4} um", home);
+                            },
+                            (None, _) => eprintln!("no window configured"),
+                            (_, None) => eprintln!("invalid home position: {}", command)
+                        }
+                    },
+                    ["position"] => eprintln!("position {:.4} um", to_um(f_position)),
+                    ["quadrant"] => eprintln!("invalid quadrant transitions {}, resyncs {}",
+                        quadrant_tracker.invalid_transitions(), quadrant_tracker.resyncs()),
+                    _ => eprintln!("unknown command: {}", command)
+                }
+            }
+        }
         refpll.tick(rising & (1 << config.bit_ref) != 0);
         if refpll.locked() {
             if rising & (1 << config.bit_meas) != 0 {
-                position = position_tracker.edge(refpll.get_phase_unwrapped());
-            }
-            let f_position = motion_filter.run(position as f64) as i64;
-            min_max_monitor.input(f_position, |position_min, position_max| {
-                let amplitude = position_max - position_min;
-                let off_duty = ((amplitude as f64)*(1.0 - config.duty_cycle)) as i64;
-                quadrant_tracker.update_limits(
-                    position_min + off_duty/2,
-                    position_max - off_duty/2);
-            });
-            quadrant_tracker.input(f_position);
-            if quadrant_tracker.up_start() {
-                fringe_counter_event(FringeCounterEvent::Start);
+                position = dmi_origin.position(position_tracker.edge(refpll.get_phase_unwrapped()));
             }
-            if quadrant_tracker.up_end() {
-                fringe_counter_event(FringeCounterEvent::End);
-            }
-            if rising & (1 << config.bit_input) != 0 {
-                fringe_counter_event(FringeCounterEvent::Fringe(position));
+            f_position = motion_filter.run(position as f64) as i64;
+            let (stroke, horizon) = match &mut turnaround_tracker {
+                Some(turnaround_tracker) =>
+                    (turnaround_tracker.input(sample_clock, f_position), turnaround_tracker.horizon()),
+                None if window.is_some() => {
+                    let (start, end) = window.unwrap().limits(config);
+                    quadrant_tracker.update_limits(start, end);
+                    quadrant_tracker.input(f_position);
+                    (None, 0)
+                },
+                None => {
+                    min_max_monitor.input(f_position, |position_min, position_max| {
+                        let amplitude = position_max - position_min;
+                        let off_duty = ((amplitude as f64)*(1.0 - config.duty_cycle)) as i64;
+                        quadrant_tracker.update_limits(
+                            position_min + off_duty/2,
+                            position_max - off_duty/2);
+                    });
+                    quadrant_tracker.input(f_position);
+                    (None, 0)
+                }
+            };
+            let meas_edge = rising & (1 << config.bit_meas) != 0;
+            for (channel, &(input, _, _)) in channels.iter().enumerate() {
+                let fringe_tagger = &mut fringe_taggers[channel];
+                events.clear();
+                let mut emit = |time, event| events.push((time, event));
+                if quadrant_tracker.up_start() {
+                    fringe_tagger.start(sample_clock, &mut emit);
+                }
+                if rising & (1 << config.inputs[input].bit) != 0 {
+                    fringe_tagger.fringe(sample_clock, position, &mut emit);
+                }
+                if meas_edge {
+                    fringe_tagger.meas_edge(sample_clock, position, &mut emit);
+                }
+                fringe_tagger.tick(sample_clock, f_position, &mut emit);
+                if quadrant_tracker.up_end() {
+                    fringe_tagger.end(sample_clock, &mut emit);
+                }
+                let (residual_sum, accepted, rejected) = model_fits[channel].get();
+                match fringe_tagger.poll(&mut emit) {
+                    Some(ModelFit::Accepted(residual)) =>
+                        model_fits[channel].set((residual_sum + residual, accepted + 1, rejected)),
+                    Some(ModelFit::Rejected) =>
+                        model_fits[channel].set((residual_sum, accepted, rejected + 1)),
+                    None => ()
+                }
+                match &mut stroke_buffers {
+                    Some(stroke_buffers) => {
+                        let stroke_buffer = &mut stroke_buffers[channel];
+                        for &(time, event) in events.iter() {
+                            if let FringeCounterEvent::Fringe(fringe) = event {
+                                stroke_buffer.push(time, fringe);
+                            }
+                        }
+                        if let Some(stroke) = stroke {
+                            stroke_buffer.stroke(stroke);
+                        }
+                        stroke_buffer.flush(fringe_tagger.unresolved(), config.duty_cycle, |time, event| {
+                            fringe_counter_event(channel, time, event);
+                        });
+                        stroke_buffer.discard_before(horizon);
+                    },
+                    None => {
+                        for &(time, event) in events.iter() {
+                            fringe_counter_event(channel, time, event);
+                        }
+                    }
+                }
             }
         } else {
             position = 0;
+            dmi_origin.reset();
             min_max_monitor.reset();
             quadrant_tracker.reset();
+            if let Some(turnaround_tracker) = &mut turnaround_tracker {
+                turnaround_tracker.reset();
+            }
+            for stroke_buffer in stroke_buffers.iter_mut().flatten() {
+                stroke_buffer.reset();
+            }
+            for fringe_tagger in fringe_taggers.iter_mut() {
+                fringe_tagger.reset();
+            }
         }
     })
 }
 
+fn load_calibration(calibration_file: &str) -> Calibration {
+    Calibration::load(calibration_file).unwrap_or_else(|e| {
+        eprintln!("failed to read calibration file {}: {}", calibration_file, e);
+        std::process::exit(1)
+    })
+}
+
+// Adds the measurement of the known wavelength to the calibration file and exits.
+fn finish_calibration(config: &Config, run: &mut CalibrationRun) {
+    let calibration_file = config.calibration_file.as_ref().unwrap();
+    let calibration = &mut run.calibration;
+    let (measured, std) = mean_std(&run.means);
+    let uncertainty = if run.means.len() > 1 {
+        std/(run.means.len() as f64).sqrt()
+    } else {
+        run.sem
+    };
+    println!("known {:.6} nm, measured {:.6} +/- {:.6} nm",
+        run.known*1.0e9, measured*1.0e9, uncertainty*1.0e9);
+    calibration.add_point(CalibrationPoint {
+        known: run.known,
+        measured: measured,
+        uncertainty: uncertainty,
+    });
+    println!("scale {:.10} +/- {:.10}, offset {:.6} +/- {:.6} nm ({} points)",
+        calibration.scale, calibration.scale_uncertainty,
+        calibration.offset*1.0e9, calibration.offset_uncertainty*1.0e9, calibration.points.len());
+    if calibration.points.len() == 1 {
+        println!("effective reference wavelength {:.6} nm", calibration.scale*config.ref_wavelength*1.0e9);
+    }
+    if let Err(e) = calibration.save(calibration_file) {
+        eprintln!("failed to write calibration file {}: {}", calibration_file, e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
 fn main() {
     let mut calibrate = false;
+    let mut filter_info = false;
+    let mut calibrate_wavelength: Option<f64> = None;
+    let mut calibration_input: Option<String> = None;
+    let mut calibration_outputs = 10;
     let mut config_file = "wavemeter.json".to_string();
     {
         let mut ap = ArgumentParser::new();
         ap.refer(&mut calibrate)
             .add_option(&["-c", "--calibrate"], StoreTrue,
-            "Calibrate scan displacement");
+            "Calibrate scan displacement, frequency, symmetry and velocity");
+        ap.refer(&mut filter_info)
+            .add_option(&["--filter-info"], StoreTrue,
+            "Print the group delay and attenuation of the motion filter");
+        ap.refer(&mut calibrate_wavelength)
+            .add_option(&["--calibrate-wavelength"], StoreOption,
+            "Calibrate against an input laser of known wavelength (in nm)");
+        ap.refer(&mut calibration_input)
+            .add_option(&["--calibration-input"], StoreOption,
+            "Name of the input used for calibration (default: first)");
+        ap.refer(&mut calibration_outputs)
+            .add_option(&["--calibration-outputs"], Store,
+            "Number of outputs averaged for calibration");
         ap.refer(&mut config_file)
             .add_option(&["--config"], Store,
             "Configuration file");
         ap.parse_args_or_exit();
     }
-    let config = read_config_from_file(config_file).unwrap();
-    if calibrate {
+    let config = read_config_from_file(config_file)
+        .unwrap_or_else(|e| { eprintln!("failed to read configuration: {}", e); std::process::exit(1) });
+    if filter_info {
+        print_motion_filter_info(&config, &create_motion_filter(&config));
+    } else if calibrate {
         do_calibrate(&config);
+    } else if let Some(known) = calibrate_wavelength {
+        let calibration = match &config.calibration_file {
+            Some(calibration_file) if Path::new(calibration_file).exists() => load_calibration(calibration_file),
+            Some(_) => Calibration::new(),
+            None => {
+                eprintln!("no calibration file configured");
+                std::process::exit(1);
+            }
+        };
+        if calibration_outputs < 1 {
+            eprintln!("--calibration-outputs must be at least 1");
+            std::process::exit(1);
+        }
+        // Decimated outputs have no standard error, so the uncertainty of the point is
+        // taken from the spread of several outputs.
+        if config.decimator.is_some() && calibration_outputs < 2 {
+            eprintln!("--calibration-outputs must be at least 2 with decimator");
+            std::process::exit(1);
+        }
+        let channel = match calibration_input {
+            Some(name) => config.inputs.iter().position(|input| input.name == name)
+                .unwrap_or_else(|| { eprintln!("unknown input: {}", name); std::process::exit(1) }),
+            None => 0
+        };
+        do_wavemeter(&config, Some(CalibrationRun {
+            channel: channel,
+            known: known*1.0e-9,
+            outputs: calibration_outputs,
+            means: Vec::new(),
+            sem: 0.0,
+            calibration: calibration,
+        }));
     } else {
-        do_wavemeter(&config);
+        do_wavemeter(&config, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(extra: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{
+            "sample_command": "", "sample_rate": 48e6, "bit_ref": 0, "bit_meas": 1,
+            "inputs": [{{"name": "input", "bit": 2}}],
+            "ref_min": 1.9e6, "ref_max": 2.1e6, "refpll_ki": 4294967, "refpll_kp": 85899345,
+            "ref_wavelength": 632.991372e-9, "position_mon_time": 0.25, "duty_cycle": 0.9,
+            "debug": false, "motion_cutoff": 100e3, "min_fringes": 1000, "fringe_jitter_tol": 0.05,
+            "decimation": 3 {}
+        }}"#, extra)).unwrap()
+    }
+
+    #[test]
+    fn config_validation() {
+        assert!(test_config("").validate().is_ok());
+        assert!(test_config(r#", "sliding_window": {"scans": 0}"#).validate().is_err());
+        assert!(test_config(r#", "sliding_window": {"time": 0.0}"#).validate().is_err());
+        assert!(test_config(r#", "fringe_position": {"polynomial": 1}"#).validate().is_err());
+        assert!(test_config(r#", "fringe_position_compare": {"polynomial": 2}"#).validate().is_ok());
+        assert!(test_config(r#", "decimator": {"cic": {"stages": 40}}"#).validate().is_ok());
+        assert!(test_config(r#", "decimator": {"cic": {"stages": 41}}"#).validate().is_err());
+        assert!(test_config(r#", "decimator": "median", "sliding_window": {"scans": 5}"#).validate().is_err());
+    }
+
+    #[test]
+    fn scan_averager_restart() {
+        let config = test_config("");
+        let mut averager = ScanAverager::new();
+        assert!(averager.input(&config, 0, FringeCounterOutput::Wavelength(1.0)).is_none());
+        assert!(averager.input(&config, 1, FringeCounterOutput::Restart).is_none());
+        assert!(averager.input(&config, 2, FringeCounterOutput::Wavelength(2.0)).is_none());
+        assert!(averager.input(&config, 3, FringeCounterOutput::Invalid).is_none());
+        let (scans, failed) = averager.input(&config, 4, FringeCounterOutput::Wavelength(4.0)).unwrap();
+        assert_eq!((scans, failed), (vec![(2, 2.0), (4, 4.0)], 1));
+    }
+
+    #[test]
+    fn average_all_rejected() {
+        let scans = [(0, 1.0), (1, 2.0), (2, 3.0), (3, 4.0)];
+        assert_eq!(average(&test_config(""), &scans, 0).unwrap().mean, 2.5);
+        assert!(average(&test_config(r#", "rejection": {"sigma_clip": 0.1}"#), &scans, 0).is_none());
+        assert!(average(&test_config(""), &[], 3).is_none());
+    }
+
+    #[test]
+    fn allan_deviation_gaps() {
+        // Alternating values, inverted after a notified gap and after a missing scan.
+        let mut allan = AllanDeviation::new();
+        for i in 0..8 {
+            allan.input(10*i, if i % 2 == 0 { 1.0 } else { -1.0 });
+        }
+        allan.gap();
+        for i in 8..16 {
+            allan.input(10*i + 5, if i % 2 == 0 { -1.0 } else { 1.0 });
+        }
+        for i in 17..25 {
+            allan.input(10*i + 5, if i % 2 == 0 { 1.0 } else { -1.0 });
+        }
+        let deviation = allan.get(1000.0);
+        assert_eq!(deviation.len(), 3);
+        assert_eq!(deviation[0].0, 1);
+        assert!((deviation[0].1 - 0.01).abs() < 1.0e-12);
+        assert!((deviation[0].2 - 2.0f64.sqrt()).abs() < 1.0e-12);
+        assert_eq!((deviation[1].0, deviation[1].2), (2, 0.0));
+        assert_eq!((deviation[2].0, deviation[2].2), (4, 0.0));
+    }
+
+    #[test]
+    fn allan_deviation_bounded() {
+        let mut allan = AllanDeviation::new();
+        for i in 0..(1 << ALLAN_MAX_OCTAVES) + 10 {
+            allan.input(i, (i % 3) as f64);
+        }
+        assert_eq!(allan.cumsum.len(), (1 << ALLAN_MAX_OCTAVES) + 1);
+        assert_eq!(allan.get(1.0).len(), ALLAN_MAX_OCTAVES);
+    }
+
+    #[test]
+    fn calibration_fit() {
+        let point = |known: f64, measured: f64| CalibrationPoint {
+            known: known,
+            measured: measured,
+            uncertainty: 1.0e-15
+        };
+        // Points on known = 1e-9 + 1.0001*measured.
+        let line = |measured: f64| 1.0e-9 + 1.0001*measured;
+        let mut calibration = Calibration::new();
+        calibration.add_point(point(line(633.0e-9), 632.9e-9));
+        assert!((calibration.apply(632.9e-9) - line(633.0e-9)).abs() < 1.0e-20);
+        assert_eq!(calibration.offset, 0.0);
+
+        // The first point is replaced.
+        calibration.add_point(point(line(633.0e-9), 633.0e-9));
+        calibration.add_point(point(line(780.0e-9), 780.0e-9));
+        calibration.add_point(point(line(1064.0e-9), 1064.0e-9));
+        assert_eq!(calibration.points.len(), 3);
+        assert!((calibration.scale - 1.0001).abs() < 1.0e-9, "{}", calibration.scale);
+        assert!((calibration.offset - 1.0e-9).abs() < 1.0e-15, "{}", calibration.offset);
+        assert!((calibration.apply(900.0e-9) - line(900.0e-9)).abs() < 1.0e-16);
+        assert!(calibration.scale_uncertainty > 0.0 && calibration.offset_uncertainty > 0.0);
+    }
+
+    #[test]
+    fn scan_decimator_envelope() {
+        let mut decimator = ScanDecimator::new(&test_config(r#", "decimator": "envelope""#)).unwrap();
+        assert!(decimator.input(FringeCounterOutput::Wavelength(780.0e-9)).is_none());
+        assert!(decimator.input(FringeCounterOutput::Invalid).is_none());
+        assert!(decimator.input(FringeCounterOutput::Wavelength(781.5e-9)).is_none());
+        let (values, failed) = decimator.input(FringeCounterOutput::Wavelength(779.0e-9)).unwrap();
+        assert_eq!(failed, 1);
+        for (value, expected) in values.iter().zip([780.1666666666666e-9, 779.0e-9, 781.5e-9].iter()) {
+            assert!((value - expected).abs() < 1.0e-17);
+        }
+    }
+
+    #[test]
+    fn least_squares_rank() {
+        let rows = |a: f64| (0..10).map(move |i| {
+            let x = i as f64;
+            (vec![1.0, x, a*x], 3.0 + 0.5*x)
+        });
+        assert!(least_squares(3, rows(2.0)).is_none());
+        let line = least_squares(2, (0..10).map(|i| (vec![1.0, i as f64], 3.0 + 0.5*(i as f64)))).unwrap();
+        assert!((line[0] - 3.0).abs() < 1.0e-12 && (line[1] - 0.5).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn motion_model_fit() {
+        // Motion with a second harmonic, with edges over two periods and a scan covering
+        // the central part of the upward stroke of the last period.
+        let period = 10000;
+        let motion = |time: u64| {
+            let phase = 2.0*std::f64::consts::PI*(time as f64)/(period as f64);
+            (-1.0e9*phase.cos() + 5.0e7*(2.0*phase).sin()) as i64
+        };
+        let points: Vec<(u64, i64)> = (0..2*period).step_by(7).map(|time| (time, motion(time))).collect();
+        let scan = (period + 1500, period + 3500);
+        assert!(MotionModel::fit(&points, period, 2, (period/2, period/2 + 2000)).is_none());
+        let model = MotionModel::fit(&points, period, 2, scan).unwrap();
+        assert!(model.residual < 1.0);
+        for &time in [scan.0, period + 2500, scan.1].iter() {
+            assert!((model.position(time) - motion(time)).abs() <= 2);
+        }
+        assert!(MotionModel::fit(&points, period, 1, scan).unwrap().residual > 1.0e6);
+    }
+
+    #[test]
+    fn fringe_counter_per_stroke() {
+        // Scans of different extents, each with fringes 100 units apart.
+        let config = test_config(r#", "turnaround_hysteresis": 100.0"#);
+        let input = Cell::new(FringeCounterEvent::Start);
+        let output = Cell::new(None);
+        let mut counter = fringe_counter(&config, &input, &output);
+        Pin::new(&mut counter).resume();
+        let mut wavelengths = Vec::new();
+        for &fringes in [1100, 1300, 900, 1050].iter() {
+            let events = std::iter::once(FringeCounterEvent::Start)
+                .chain((0..fringes).map(|k| FringeCounterEvent::Fringe(100*k)))
+                .chain(std::iter::once(FringeCounterEvent::End));
+            for event in events {
+                input.set(event);
+                Pin::new(&mut counter).resume();
+                match output.take() {
+                    Some(FringeCounterOutput::Wavelength(wavelength)) => wavelengths.push(wavelength),
+                    Some(FringeCounterOutput::Invalid) => wavelengths.push(0.0),
+                    Some(FringeCounterOutput::Restart) => panic!("restart"),
+                    None => ()
+                }
+            }
+        }
+        let expected = 100.0*config.ref_wavelength/(noptica::Dpll::TURN as f64);
+        assert_eq!(wavelengths.len(), 4);
+        assert_eq!(wavelengths[2], 0.0);
+        for &wavelength in [wavelengths[0], wavelengths[1], wavelengths[3]].iter() {
+            assert!((wavelength - expected).abs() < 1.0e-9*expected);
+        }
+    }
+
+    #[test]
+    fn window_limits_and_origin() {
+        let mut config = test_config("");
+        config.ref_wavelength = 1.0e-6;
+        let turn = noptica::Dpll::TURN;
+        let close = |(a, b): (i64, i64), (c, d): (i64, i64)| (a - c).abs() <= 1 && (b - d).abs() <= 1;
+        let window = WindowConfig { start: 2.0, end: -1.0, home: None };
+        assert!(close(window.limits(&config), (-turn, 2*turn)));
+        let window = WindowConfig { start: 2.0, end: -1.0, home: Some(10.0) };
+        assert!(close(window.limits(&config), (9*turn, 12*turn)));
+
+        // The origin is taken at the first MEAS edge, and again after a loss of lock.
+        let mut origin = DmiOrigin::new();
+        assert_eq!(origin.position(12345), 0);
+        assert_eq!(origin.position(12400), 55);
+        origin.reset();
+        assert_eq!(origin.position(-500), 0);
+        assert_eq!(origin.position(-400), 100);
+    }
+
+    #[test]
+    fn stroke_buffer_bounded() {
+        let mut buffer = StrokeBuffer::new();
+        for time in 0..100 {
+            buffer.push(time, 10*(time as i64));
+        }
+        buffer.stroke(Stroke { start: (20, 200), end: (60, 600) });
+        buffer.discard_before(80);
+        assert_eq!(buffer.fringes.front(), Some(&(20, 200)));
+        let mut events = Vec::new();
+        buffer.flush(Some(61), 0.5, |time, event| events.push((time, event)));
+        assert_eq!(events.len(), 21);
+        assert_eq!(events[1], (31, FringeCounterEvent::Fringe(310)));
+        buffer.discard_before(80);
+        assert_eq!(buffer.fringes.len(), 20);
+    }
+
+    #[test]
+    fn fringe_tagger_order() {
+        // The End event waits for the interpolation of the fringe preceding it, and the
+        // Start event at the time of a fringe precedes it.
+        let config = test_config("");
+        let mut tagger = FringeTagger::new(&config, FringePosition::Linear, 0);
+        let mut events = Vec::new();
+        tagger.meas_edge(0, 0, |time, event| events.push((time, event)));
+        tagger.meas_edge(10, 100, |time, event| events.push((time, event)));
+        tagger.fringe(15, 0, |time, event| events.push((time, event)));
+        tagger.start(15, |time, event| events.push((time, event)));
+        tagger.fringe(16, 0, |time, event| events.push((time, event)));
+        tagger.end(17, |time, event| events.push((time, event)));
+        assert_eq!(events, vec![(15, FringeCounterEvent::Start)]);
+        tagger.meas_edge(20, 200, |time, event| events.push((time, event)));
+        assert_eq!(events, vec![
+            (15, FringeCounterEvent::Start),
+            (15, FringeCounterEvent::Fringe(150)),
+            (16, FringeCounterEvent::Fringe(160)),
+            (17, FringeCounterEvent::End)]);
+    }
+
+    fn run_quadrants(tracker: &mut QuadrantTracker, positions: &[i64]) -> (usize, usize) {
+        let (mut starts, mut ends) = (0, 0);
+        for &position in positions.iter() {
+            tracker.input(position);
+            starts += tracker.up_start() as usize;
+            ends += tracker.up_end() as usize;
+        }
+        (starts, ends)
+    }
+
+    #[test]
+    fn quadrant_tracker_hysteresis() {
+        // The limits apply from the first crossing of the middle. Two scans follow, with
+        // noise of 15 around each limit.
+        let positions = [-50, 10, -101, -85, 101, 95, 85, 105, 0, -95, -101, -95, -85, 101, 50];
+        let mut tracker = QuadrantTracker::with_tuning(10, 0, 0);
+        tracker.update_limits(-100, 100);
+        assert_eq!(run_quadrants(&mut tracker, &positions), (2, 2));
+        assert_eq!(tracker.invalid_transitions(), 0);
+
+        let mut tracker = QuadrantTracker::new();
+        tracker.update_limits(-100, 100);
+        run_quadrants(&mut tracker, &positions);
+        assert!(tracker.invalid_transitions() > 0);
     }
 }